@@ -0,0 +1,176 @@
+// Moves per second through the whole server: JSON over WebSockets, move validation in
+// each room and storing the moves. Runs the real binary on its own port with a fresh
+// database file and plays the same game on thousands of boards at once.
+//
+//     cargo bench --bench moves
+//     BENCH_GAMES=5000 cargo bench --bench moves
+#![allow(clippy::needless_return)]
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::stream::{self, StreamExt};
+use futures::SinkExt;
+use serde_json::{json, Value};
+use std::net::TcpStream as StdTcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const PORT: u16 = 18080;
+// How many games are set up at once; more makes the listen backlog overflow.
+const CONNECT_CONCURRENCY: usize = 64;
+// Morphy's Opera Game: 33 plies ending in mate, never repeating a position.
+const MOVES: [&str; 33] = [
+    "e2e4", "e7e5", "g1f3", "d7d6", "d2d4", "c8g4", "d4e5", "g4f3", "d1f3", "d6e5", "f1c4", "g8f6",
+    "f3b3", "d8e7", "b1c3", "c7c6", "c1g5", "b7b5", "c3b5", "c6b5", "c4b5", "b8d7", "e1c1", "a8d8",
+    "d1d7", "d8d7", "h1d1", "e7e6", "b5d7", "f6d7", "b3b8", "d7b8", "d1d8",
+];
+
+// Kills the server and removes its database when the benchmark is done.
+struct ServerProcess(Child, PathBuf);
+
+impl ServerProcess {
+    fn start() -> ServerProcess {
+        let db = std::env::temp_dir().join(format!("chess-bench-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let child = Command::new(env!("CARGO_BIN_EXE_chess-server"))
+            .env("CHESS_PORT", PORT.to_string())
+            .env("CHESS_DB", &db)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("cannot start chess-server");
+        let started = Instant::now();
+        while StdTcpStream::connect(("127.0.0.1", PORT)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        return ServerProcess(child, db);
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+        let _ = std::fs::remove_file(&self.1);
+    }
+}
+
+async fn connect() -> Ws {
+    let mut request = format!("ws://127.0.0.1:{}/ws", PORT)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "chess.v2".parse().unwrap());
+    let (mut ws, _) = connect_async(request).await.unwrap();
+    expect(&mut ws, "Start").await;
+    return ws;
+}
+
+async fn send(ws: &mut Ws, msg: Value) {
+    ws.send(Message::Text(msg.to_string())).await.unwrap();
+}
+
+// Reads until the named event arrives, skipping anything else.
+async fn expect(ws: &mut Ws, event: &str) -> Value {
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => {
+                let msg: Value = serde_json::from_str(&text).unwrap();
+                if msg["event"] == event {
+                    return msg;
+                }
+                assert!(msg["msg"]["code"].is_null(), "server error: {}", text);
+            }
+            Some(Ok(_)) => {}
+            other => panic!("connection lost waiting for {}: {:?}", event, other),
+        }
+    }
+}
+
+struct Game {
+    code: String,
+    white: Ws,
+    black: Ws,
+}
+
+async fn open_game() -> Game {
+    let mut white = connect().await;
+    send(&mut white, json!({"event": "GetCode", "name": "white"})).await;
+    let created = expect(&mut white, "GetCode").await;
+    let code = created["msg"]["code"].as_str().unwrap().to_string();
+    let mut black = connect().await;
+    send(
+        &mut black,
+        json!({"event": "ConnectWith", "name": "black", "room_code": &code}),
+    )
+    .await;
+    expect(&mut black, "ConnectWith").await;
+    expect(&mut white, "ConnectWith").await;
+    return Game { code, white, black };
+}
+
+// Row 0 is the eighth rank.
+fn square(uci: &str) -> (u8, u8) {
+    let bytes = uci.as_bytes();
+    return (b'8' - bytes[1], bytes[0] - b'a');
+}
+
+// Each move waits for the mover's MoveAccepted and the opponent's Move.
+async fn play(mut game: Game) {
+    for (ply, uci) in MOVES.iter().enumerate() {
+        let (mover, opponent) = if ply % 2 == 0 {
+            (&mut game.white, &mut game.black)
+        } else {
+            (&mut game.black, &mut game.white)
+        };
+        let (i, j) = square(&uci[0..2]);
+        let (k, l) = square(&uci[2..4]);
+        let msg = json!({"event": "Move", "room_code": &game.code, "i": i, "j": j, "k": k, "l": l});
+        send(mover, msg).await;
+        expect(mover, "MoveAccepted").await;
+        expect(opponent, "Move").await;
+    }
+}
+
+fn moves_per_second(c: &mut Criterion) {
+    let games: usize = std::env::var("BENCH_GAMES")
+        .ok()
+        .and_then(|games| games.parse().ok())
+        .unwrap_or(2000);
+    let _server = ServerProcess::start();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("moves");
+    group.throughput(Throughput::Elements((games * MOVES.len()) as u64));
+    group.sample_size(10);
+    group.bench_function(format!("{} concurrent games", games), |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                // Only the moves are timed, not connecting and seating the players.
+                let opened: Vec<Game> = stream::iter(0..games)
+                    .map(|_| open_game())
+                    .buffer_unordered(CONNECT_CONCURRENCY)
+                    .collect()
+                    .await;
+                let started = Instant::now();
+                futures::future::join_all(opened.into_iter().map(play)).await;
+                elapsed += started.elapsed();
+            }
+            return elapsed;
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, moves_per_second);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordVerifier};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How long a session token stays valid after login.
+const TOKEN_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+// Tokens are signed with CHESS_SECRET. Without it a random key is used, so every
+// session ends when the server restarts.
+static SECRET: Lazy<Vec<u8>> = Lazy::new(|| match std::env::var("CHESS_SECRET") {
+    Ok(secret) if !secret.is_empty() => return secret.into_bytes(),
+    _ => {
+        println!("CHESS_SECRET is not set; sessions will not survive a restart");
+        return rand::thread_rng().gen::<[u8; 32]>().to_vec();
+    }
+});
+
+// A registered player, as carried by a session token.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    id: i64,
+    name: String,
+    // Seconds since the unix epoch.
+    exp: u64,
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+}
+
+fn sign(payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&SECRET).unwrap();
+    mac.update(payload.as_bytes());
+    return mac;
+}
+
+// Names are shown to other players and key the ratings, so keep them plain.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if name.len() < 3 || name.len() > 20 || !valid_chars {
+        return Err(String::from(
+            "Names are 3 to 20 letters, digits, '_' or '-'",
+        ));
+    }
+    return Ok(());
+}
+
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.len() < 8 {
+        return Err(String::from("Passwords need at least 8 characters"));
+    }
+    return Ok(());
+}
+
+// Slow on purpose; call it off the async workers.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    return Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string());
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => {
+            return Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        }
+        Err(_) => return false,
+    }
+}
+
+// A token is the base64 encoded claims and their HMAC-SHA256, joined by a dot.
+pub fn issue_token(user: &User) -> String {
+    let claims = Claims {
+        id: user.id,
+        name: user.name.clone(),
+        exp: now_secs() + TOKEN_TTL_SECS,
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+    let signature = URL_SAFE_NO_PAD.encode(sign(&payload).finalize().into_bytes());
+    return format!("{}.{}", payload, signature);
+}
+
+pub fn verify_token(token: &str) -> Result<User, String> {
+    let (payload, signature) = token
+        .split_once('.')
+        .ok_or_else(|| String::from("Malformed token"))?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| String::from("Malformed token"))?;
+    sign(payload)
+        .verify_slice(&signature)
+        .map_err(|_| String::from("Bad token signature"))?;
+    let claims: Claims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| String::from("Malformed token"))?;
+    if claims.exp < now_secs() {
+        return Err(String::from("Token expired"));
+    }
+    return Ok(User {
+        id: claims.id,
+        name: claims.name,
+    });
+}
+
+// Who may take the open seat of a room. The default lets in anyone with the code.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Access {
+    // Room passwords are checked on every join, so they get a keyed hash, not argon2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    // Only registered names are safe to invite; a guest may use any free name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invitee: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allowed_users: Vec<i64>,
+}
+
+impl Access {
+    pub fn new(password: Option<&str>, invitee: Option<String>, allowed_users: Vec<i64>) -> Access {
+        return Access {
+            password_hash: password
+                .filter(|password| !password.is_empty())
+                .map(|password| URL_SAFE_NO_PAD.encode(room_mac(password).finalize().into_bytes())),
+            invitee: invitee.filter(|invitee| !invitee.is_empty()),
+            allowed_users,
+        };
+    }
+    pub fn is_open(&self) -> bool {
+        return self.password_hash.is_none()
+            && self.invitee.is_none()
+            && self.allowed_users.is_empty();
+    }
+    // The password, if any, must match, and when invitees are named the joiner must be
+    // one of them, by name or by user id.
+    pub fn check(
+        &self,
+        name: &str,
+        user_id: Option<i64>,
+        password: Option<&str>,
+    ) -> Result<(), String> {
+        if let Some(ref hash) = self.password_hash {
+            let matches = match (password, URL_SAFE_NO_PAD.decode(hash)) {
+                (Some(password), Ok(hash)) => room_mac(password).verify_slice(&hash).is_ok(),
+                _ => false,
+            };
+            if !matches {
+                return Err(String::from("Wrong room password"));
+            }
+        }
+        let restricted = self.invitee.is_some() || !self.allowed_users.is_empty();
+        let invited = self
+            .invitee
+            .as_ref()
+            .is_some_and(|invitee| invitee.eq_ignore_ascii_case(name))
+            || user_id.is_some_and(|id| self.allowed_users.contains(&id));
+        if restricted && !invited {
+            return Err(String::from("This room is invite only"));
+        }
+        return Ok(());
+    }
+}
+
+// Without CHESS_SECRET the key changes on restart and restored rooms lose their password,
+// so their open seat can no longer be taken.
+fn room_mac(password: &str) -> Hmac<Sha256> {
+    return sign(&format!("room:{}", password));
+}
@@ -0,0 +1,1116 @@
+use serde::{Deserialize, Serialize};
+
+// (row, column); row 0 is black's back rank and row 7 is white's.
+pub type Square = (u8, u8);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    pub fn opposite(&self) -> Color {
+        match self {
+            Color::White => return Color::Black,
+            Color::Black => return Color::White,
+        }
+    }
+    fn home_row(&self) -> u8 {
+        match self {
+            Color::White => return 7,
+            Color::Black => return 0,
+        }
+    }
+    fn pawn_dir(&self) -> i8 {
+        match self {
+            Color::White => return -1,
+            Color::Black => return 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PieceKind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+impl PieceKind {
+    fn fen_char(&self) -> char {
+        match self {
+            PieceKind::Pawn => return 'p',
+            PieceKind::Knight => return 'n',
+            PieceKind::Bishop => return 'b',
+            PieceKind::Rook => return 'r',
+            PieceKind::Queen => return 'q',
+            PieceKind::King => return 'k',
+        }
+    }
+    fn from_fen_char(c: char) -> Option<PieceKind> {
+        match c.to_ascii_lowercase() {
+            'p' => return Some(PieceKind::Pawn),
+            'n' => return Some(PieceKind::Knight),
+            'b' => return Some(PieceKind::Bishop),
+            'r' => return Some(PieceKind::Rook),
+            'q' => return Some(PieceKind::Queen),
+            'k' => return Some(PieceKind::King),
+            _ => return None,
+        }
+    }
+    // Promotion codes used by the client: "H" is the knight.
+    pub fn from_promotion_code(code: &str) -> Option<PieceKind> {
+        match code {
+            "H" => return Some(PieceKind::Knight),
+            "B" => return Some(PieceKind::Bishop),
+            "R" => return Some(PieceKind::Rook),
+            "Q" => return Some(PieceKind::Queen),
+            _ => return None,
+        }
+    }
+    pub fn promotion_code(&self) -> Option<&'static str> {
+        match self {
+            PieceKind::Knight => return Some("H"),
+            PieceKind::Bishop => return Some("B"),
+            PieceKind::Rook => return Some("R"),
+            PieceKind::Queen => return Some("Q"),
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Piece {
+    pub kind: PieceKind,
+    pub color: Color,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CastlingRights {
+    pub white_king_side: bool,
+    pub white_queen_side: bool,
+    pub black_king_side: bool,
+    pub black_queen_side: bool,
+}
+
+impl CastlingRights {
+    fn get(&self, color: Color, king_side: bool) -> bool {
+        match (color, king_side) {
+            (Color::White, true) => return self.white_king_side,
+            (Color::White, false) => return self.white_queen_side,
+            (Color::Black, true) => return self.black_king_side,
+            (Color::Black, false) => return self.black_queen_side,
+        }
+    }
+    fn clear(&mut self, color: Color, king_side: bool) {
+        match (color, king_side) {
+            (Color::White, true) => self.white_king_side = false,
+            (Color::White, false) => self.white_queen_side = false,
+            (Color::Black, true) => self.black_king_side = false,
+            (Color::Black, false) => self.black_queen_side = false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveKind {
+    Normal,
+    DoublePush,
+    EnPassant,
+    CastleKingSide,
+    CastleQueenSide,
+    Promotion,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MoveError {
+    EmptySquare,
+    NotYourPiece,
+    OwnPieceOnTarget,
+    InvalidPieceMove,
+    PathBlocked,
+    CastlingNotAllowed,
+    CastlingOutOfCheck,
+    CastlingThroughCheck,
+    LeavesKingInCheck,
+    IgnoresCheck,
+    PromotionRequired,
+}
+
+impl MoveError {
+    pub fn to_string(&self) -> String {
+        match self {
+            MoveError::EmptySquare => return String::from("No piece on that square"),
+            MoveError::NotYourPiece => return String::from("That piece is not yours"),
+            MoveError::OwnPieceOnTarget => {
+                return String::from("Target square is occupied by your own piece")
+            }
+            MoveError::InvalidPieceMove => return String::from("That piece cannot move there"),
+            MoveError::PathBlocked => return String::from("The path is blocked"),
+            MoveError::CastlingNotAllowed => return String::from("Castling is not allowed"),
+            MoveError::CastlingOutOfCheck => return String::from("Cannot castle out of check"),
+            MoveError::CastlingThroughCheck => {
+                return String::from("Cannot castle through an attacked square")
+            }
+            MoveError::LeavesKingInCheck => {
+                return String::from("Move would leave your king in check")
+            }
+            MoveError::IgnoresCheck => return String::from("Your king is in check"),
+            MoveError::PromotionRequired => return String::from("A promotion piece is required"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum GameResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2")]
+    Draw,
+    // Game ended without a result, e.g. aborted before it really started.
+    #[serde(rename = "*")]
+    NoResult,
+}
+
+impl GameResult {
+    pub fn win_for(color: Color) -> GameResult {
+        match color {
+            Color::White => return GameResult::WhiteWins,
+            Color::Black => return GameResult::BlackWins,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum GameOverReason {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    FiftyMoveRule,
+    ThreefoldRepetition,
+    Timeout,
+    TimeoutVsInsufficientMaterial,
+    Abandoned,
+    Aborted,
+    Resignation,
+    Agreement,
+}
+
+// Everything that makes two positions the same for repetition purposes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PositionKey {
+    squares: [[Option<Piece>; 8]; 8],
+    side_to_move: Color,
+    castling: CastlingRights,
+    en_passant: Option<Square>,
+}
+
+const KNIGHT_STEPS: [(i8, i8); 8] = [
+    (-2, -1),
+    (-2, 1),
+    (-1, -2),
+    (-1, 2),
+    (1, -2),
+    (1, 2),
+    (2, -1),
+    (2, 1),
+];
+const KING_STEPS: [(i8, i8); 8] = [
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, -1),
+    (0, 1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+];
+const ROOK_DIRS: [(i8, i8); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+const BISHOP_DIRS: [(i8, i8); 4] = [(-1, -1), (-1, 1), (1, -1), (1, 1)];
+
+pub fn square_name((i, j): Square) -> String {
+    return format!("{}{}", (b'a' + j) as char, 8 - i);
+}
+
+// Long algebraic notation as used by UCI engines, e.g. "e2e4" or "e7e8q".
+pub fn uci((from, to): (Square, Square), promotion: Option<PieceKind>) -> String {
+    let mut uci = square_name(from) + &square_name(to);
+    if let Some(piece) = promotion {
+        uci.push(piece.fen_char());
+    }
+    return uci;
+}
+
+pub fn parse_uci(uci: &str) -> Option<((Square, Square), Option<PieceKind>)> {
+    let from = parse_square(uci.get(0..2)?)?;
+    let to = parse_square(uci.get(2..4)?)?;
+    let mut rest = uci.get(4..)?.chars();
+    let promotion = match (rest.next(), rest.next()) {
+        (None, _) => None,
+        (Some(c), None) => {
+            Some(PieceKind::from_fen_char(c).filter(|p| p.promotion_code().is_some())?)
+        }
+        _ => return None,
+    };
+    return Some(((from, to), promotion));
+}
+
+pub fn parse_square(name: &str) -> Option<Square> {
+    let bytes = name.as_bytes();
+    if bytes.len() != 2 || !(b'a'..=b'h').contains(&bytes[0]) || !(b'1'..=b'8').contains(&bytes[1])
+    {
+        return None;
+    }
+    return Some((b'8' - bytes[1], bytes[0] - b'a'));
+}
+
+fn offset((i, j): Square, (di, dj): (i8, i8)) -> Option<Square> {
+    let (r, c) = (i as i8 + di, j as i8 + dj);
+    if (0..8).contains(&r) && (0..8).contains(&c) {
+        return Some((r as u8, c as u8));
+    }
+    return None;
+}
+
+#[derive(Clone)]
+pub struct Board {
+    squares: [[Option<Piece>; 8]; 8],
+    pub side_to_move: Color,
+    pub castling: CastlingRights,
+    pub en_passant: Option<Square>,
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+impl Board {
+    pub fn new() -> Board {
+        let back_rank = [
+            PieceKind::Rook,
+            PieceKind::Knight,
+            PieceKind::Bishop,
+            PieceKind::Queen,
+            PieceKind::King,
+            PieceKind::Bishop,
+            PieceKind::Knight,
+            PieceKind::Rook,
+        ];
+        let mut squares = [[None; 8]; 8];
+        for (j, kind) in back_rank.iter().enumerate() {
+            squares[0][j] = Some(Piece {
+                kind: *kind,
+                color: Color::Black,
+            });
+            squares[1][j] = Some(Piece {
+                kind: PieceKind::Pawn,
+                color: Color::Black,
+            });
+            squares[6][j] = Some(Piece {
+                kind: PieceKind::Pawn,
+                color: Color::White,
+            });
+            squares[7][j] = Some(Piece {
+                kind: *kind,
+                color: Color::White,
+            });
+        }
+        return Board {
+            squares,
+            side_to_move: Color::White,
+            castling: CastlingRights {
+                white_king_side: true,
+                white_queen_side: true,
+                black_king_side: true,
+                black_queen_side: true,
+            },
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        };
+    }
+
+    pub fn from_fen(fen: &str) -> Result<Board, String> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() < 4 || fields.len() > 6 {
+            return Err(String::from("FEN must have between 4 and 6 fields"));
+        }
+
+        let rows: Vec<&str> = fields[0].split('/').collect();
+        if rows.len() != 8 {
+            return Err(String::from("FEN board must have 8 ranks"));
+        }
+        let mut squares = [[None; 8]; 8];
+        for (i, row) in rows.iter().enumerate() {
+            let mut j = 0;
+            for c in row.chars() {
+                if j >= 8 {
+                    return Err(format!("FEN rank {} has more than 8 squares", 8 - i));
+                }
+                if let Some(skip) = c.to_digit(10) {
+                    j += skip as usize;
+                } else {
+                    let kind = PieceKind::from_fen_char(c)
+                        .ok_or(format!("Unknown piece '{}' in FEN", c))?;
+                    let color = if c.is_ascii_uppercase() {
+                        Color::White
+                    } else {
+                        Color::Black
+                    };
+                    squares[i][j] = Some(Piece { kind, color });
+                    j += 1;
+                }
+            }
+            if j != 8 {
+                return Err(format!("FEN rank {} does not have 8 squares", 8 - i));
+            }
+        }
+        for i in [0, 7] {
+            if squares[i]
+                .iter()
+                .any(|p| matches!(p, Some(p) if p.kind == PieceKind::Pawn))
+            {
+                return Err(String::from("Pawns cannot stand on the first or last rank"));
+            }
+        }
+
+        let side_to_move = match fields[1] {
+            "w" => Color::White,
+            "b" => Color::Black,
+            _ => return Err(String::from("Side to move must be 'w' or 'b'")),
+        };
+
+        let mut castling = CastlingRights {
+            white_king_side: false,
+            white_queen_side: false,
+            black_king_side: false,
+            black_queen_side: false,
+        };
+        if fields[2] != "-" {
+            for c in fields[2].chars() {
+                match c {
+                    'K' => castling.white_king_side = true,
+                    'Q' => castling.white_queen_side = true,
+                    'k' => castling.black_king_side = true,
+                    'q' => castling.black_queen_side = true,
+                    _ => return Err(format!("Invalid castling flag '{}' in FEN", c)),
+                }
+            }
+        }
+        // A right is only kept while the king and that rook are still on their home squares.
+        for color in [Color::White, Color::Black] {
+            let row = color.home_row() as usize;
+            let home = |col: usize, kind| squares[row][col] == Some(Piece { kind, color });
+            for (king_side, rook_col) in [(true, 7), (false, 0)] {
+                if !home(4, PieceKind::King) || !home(rook_col, PieceKind::Rook) {
+                    castling.clear(color, king_side);
+                }
+            }
+        }
+
+        let en_passant = match fields[3] {
+            "-" => None,
+            name => {
+                let sq = parse_square(name).ok_or("Invalid en passant square in FEN")?;
+                let expected_row = if side_to_move == Color::White { 2 } else { 5 };
+                if sq.0 != expected_row {
+                    return Err(String::from("En passant square is on the wrong rank"));
+                }
+                // The pawn that just moved two squares stands in front of it, and the
+                // squares it crossed are empty.
+                let mover = side_to_move.opposite();
+                let dir = mover.pawn_dir();
+                let pawn_row = (sq.0 as i8 + dir) as usize;
+                let start_row = (sq.0 as i8 - dir) as usize;
+                let pushed = squares[pawn_row][sq.1 as usize]
+                    == Some(Piece {
+                        kind: PieceKind::Pawn,
+                        color: mover,
+                    });
+                if !pushed
+                    || squares[sq.0 as usize][sq.1 as usize].is_some()
+                    || squares[start_row][sq.1 as usize].is_some()
+                {
+                    return Err(String::from(
+                        "En passant square does not follow a double pawn push",
+                    ));
+                }
+                Some(sq)
+            }
+        };
+
+        let halfmove_clock = match fields.get(4) {
+            Some(n) => n
+                .parse::<u32>()
+                .map_err(|_| "Invalid halfmove clock in FEN")?,
+            None => 0,
+        };
+        let fullmove_number = match fields.get(5) {
+            Some(n) => n
+                .parse::<u32>()
+                .map_err(|_| "Invalid fullmove number in FEN")?,
+            None => 1,
+        };
+
+        let board = Board {
+            squares,
+            side_to_move,
+            castling,
+            en_passant,
+            halfmove_clock,
+            fullmove_number: fullmove_number.max(1),
+        };
+        for color in [Color::White, Color::Black] {
+            let kings = board
+                .squares
+                .iter()
+                .flatten()
+                .filter(|p| {
+                    **p == Some(Piece {
+                        kind: PieceKind::King,
+                        color,
+                    })
+                })
+                .count();
+            if kings != 1 {
+                return Err(String::from("Each side must have exactly one king"));
+            }
+        }
+        if board.is_in_check(side_to_move.opposite()) {
+            return Err(String::from("The side not to move is in check"));
+        }
+        return Ok(board);
+    }
+
+    pub fn to_fen(&self) -> String {
+        let mut placement = Vec::new();
+        for row in self.squares.iter() {
+            let mut rank = String::new();
+            let mut empty = 0;
+            for sq in row.iter() {
+                match sq {
+                    None => empty += 1,
+                    Some(p) => {
+                        if empty > 0 {
+                            rank.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        let c = p.kind.fen_char();
+                        rank.push(if p.color == Color::White {
+                            c.to_ascii_uppercase()
+                        } else {
+                            c
+                        });
+                    }
+                }
+            }
+            if empty > 0 {
+                rank.push_str(&empty.to_string());
+            }
+            placement.push(rank);
+        }
+
+        let mut castling = String::new();
+        for (allowed, c) in [
+            (self.castling.white_king_side, 'K'),
+            (self.castling.white_queen_side, 'Q'),
+            (self.castling.black_king_side, 'k'),
+            (self.castling.black_queen_side, 'q'),
+        ] {
+            if allowed {
+                castling.push(c);
+            }
+        }
+        if castling.is_empty() {
+            castling.push('-');
+        }
+
+        return format!(
+            "{} {} {} {} {} {}",
+            placement.join("/"),
+            if self.side_to_move == Color::White {
+                "w"
+            } else {
+                "b"
+            },
+            castling,
+            self.en_passant.map_or(String::from("-"), square_name),
+            self.halfmove_clock,
+            self.fullmove_number
+        );
+    }
+
+    pub fn piece_at(&self, (i, j): Square) -> Option<Piece> {
+        return self.squares[i as usize][j as usize];
+    }
+
+    fn set(&mut self, (i, j): Square, piece: Option<Piece>) {
+        self.squares[i as usize][j as usize] = piece;
+    }
+
+    fn king_square(&self, color: Color) -> Option<Square> {
+        for i in 0..8 {
+            for j in 0..8 {
+                if self.piece_at((i, j))
+                    == Some(Piece {
+                        kind: PieceKind::King,
+                        color,
+                    })
+                {
+                    return Some((i, j));
+                }
+            }
+        }
+        return None;
+    }
+
+    pub fn is_in_check(&self, color: Color) -> bool {
+        match self.king_square(color) {
+            Some(sq) => return self.is_attacked(sq, color.opposite()),
+            None => return false,
+        }
+    }
+
+    // Whether any piece of `by` attacks `sq`, ignoring pins.
+    fn is_attacked(&self, sq: Square, by: Color) -> bool {
+        let has = |s: Option<Square>, kinds: &[PieceKind]| {
+            if let Some(p) = s.and_then(|s| self.piece_at(s)) {
+                return p.color == by && kinds.contains(&p.kind);
+            }
+            return false;
+        };
+        // A pawn of `by` attacks from one row behind its direction of travel.
+        for dj in [-1, 1] {
+            if has(offset(sq, (-by.pawn_dir(), dj)), &[PieceKind::Pawn]) {
+                return true;
+            }
+        }
+        for step in KNIGHT_STEPS {
+            if has(offset(sq, step), &[PieceKind::Knight]) {
+                return true;
+            }
+        }
+        for step in KING_STEPS {
+            if has(offset(sq, step), &[PieceKind::King]) {
+                return true;
+            }
+        }
+        for (dirs, kinds) in [
+            (ROOK_DIRS, [PieceKind::Rook, PieceKind::Queen]),
+            (BISHOP_DIRS, [PieceKind::Bishop, PieceKind::Queen]),
+        ] {
+            for dir in dirs {
+                let mut cur = offset(sq, dir);
+                while let Some(s) = cur {
+                    if self.piece_at(s).is_some() {
+                        if has(Some(s), &kinds) {
+                            return true;
+                        }
+                        break;
+                    }
+                    cur = offset(s, dir);
+                }
+            }
+        }
+        return false;
+    }
+
+    fn check_path(&self, from: Square, to: Square) -> Result<(), MoveError> {
+        let di = (to.0 as i8 - from.0 as i8).signum();
+        let dj = (to.1 as i8 - from.1 as i8).signum();
+        let mut cur = offset(from, (di, dj)).unwrap();
+        while cur != to {
+            if self.piece_at(cur).is_some() {
+                return Err(MoveError::PathBlocked);
+            }
+            cur = offset(cur, (di, dj)).unwrap();
+        }
+        return Ok(());
+    }
+
+    fn check_pawn_move(
+        &self,
+        color: Color,
+        from: Square,
+        to: Square,
+    ) -> Result<MoveKind, MoveError> {
+        let dir = color.pawn_dir();
+        let di = to.0 as i8 - from.0 as i8;
+        let dj = to.1 as i8 - from.1 as i8;
+        let start_row = color.home_row() as i8 + dir;
+        let kind = if dj == 0 && di == dir {
+            if self.piece_at(to).is_some() {
+                return Err(MoveError::PathBlocked);
+            }
+            MoveKind::Normal
+        } else if dj == 0 && di == 2 * dir && from.0 as i8 == start_row {
+            if self.piece_at(offset(from, (dir, 0)).unwrap()).is_some()
+                || self.piece_at(to).is_some()
+            {
+                return Err(MoveError::PathBlocked);
+            }
+            MoveKind::DoublePush
+        } else if dj.abs() == 1 && di == dir {
+            if self.piece_at(to).is_some() {
+                MoveKind::Normal
+            } else if self.en_passant == Some(to) {
+                MoveKind::EnPassant
+            } else {
+                return Err(MoveError::InvalidPieceMove);
+            }
+        } else {
+            return Err(MoveError::InvalidPieceMove);
+        };
+        if to.0 == color.opposite().home_row() {
+            return Ok(MoveKind::Promotion);
+        }
+        return Ok(kind);
+    }
+
+    fn check_castle(&self, color: Color, from: Square, to: Square) -> Result<MoveKind, MoveError> {
+        let row = color.home_row();
+        let king_side = to.1 == 6;
+        if from != (row, 4) || !self.castling.get(color, king_side) {
+            return Err(MoveError::CastlingNotAllowed);
+        }
+        let rook_sq = if king_side { (row, 7) } else { (row, 0) };
+        if self.piece_at(rook_sq)
+            != Some(Piece {
+                kind: PieceKind::Rook,
+                color,
+            })
+        {
+            return Err(MoveError::CastlingNotAllowed);
+        }
+        self.check_path(from, rook_sq)?;
+        if self.is_in_check(color) {
+            return Err(MoveError::CastlingOutOfCheck);
+        }
+        let passing = if king_side { (row, 5) } else { (row, 3) };
+        if self.is_attacked(passing, color.opposite()) {
+            return Err(MoveError::CastlingThroughCheck);
+        }
+        if king_side {
+            return Ok(MoveKind::CastleKingSide);
+        }
+        return Ok(MoveKind::CastleQueenSide);
+    }
+
+    // Validates a move for the side to move without changing the board.
+    pub fn check_move(&self, from: Square, to: Square) -> Result<MoveKind, MoveError> {
+        let piece = self.piece_at(from).ok_or(MoveError::EmptySquare)?;
+        if piece.color != self.side_to_move {
+            return Err(MoveError::NotYourPiece);
+        }
+        if let Some(target) = self.piece_at(to) {
+            if target.color == piece.color {
+                return Err(MoveError::OwnPieceOnTarget);
+            }
+        }
+        let di = (to.0 as i8 - from.0 as i8).abs();
+        let dj = (to.1 as i8 - from.1 as i8).abs();
+        let straight = di == 0 || dj == 0;
+        let diagonal = di == dj;
+        let kind = match piece.kind {
+            PieceKind::Pawn => self.check_pawn_move(piece.color, from, to)?,
+            PieceKind::Knight if (di, dj) == (1, 2) || (di, dj) == (2, 1) => MoveKind::Normal,
+            PieceKind::Bishop if diagonal => {
+                self.check_path(from, to)?;
+                MoveKind::Normal
+            }
+            PieceKind::Rook if straight => {
+                self.check_path(from, to)?;
+                MoveKind::Normal
+            }
+            PieceKind::Queen if straight || diagonal => {
+                self.check_path(from, to)?;
+                MoveKind::Normal
+            }
+            PieceKind::King if di <= 1 && dj <= 1 => MoveKind::Normal,
+            PieceKind::King if di == 0 && dj == 2 => self.check_castle(piece.color, from, to)?,
+            _ => return Err(MoveError::InvalidPieceMove),
+        };
+        let mut after = self.clone();
+        after.play(from, to, kind, PieceKind::Queen);
+        if after.is_in_check(piece.color) {
+            if self.is_in_check(piece.color) {
+                return Err(MoveError::IgnoresCheck);
+            }
+            return Err(MoveError::LeavesKingInCheck);
+        }
+        return Ok(kind);
+    }
+
+    // Validates and plays a move. Promotions must name the piece to promote to.
+    pub fn make_move(
+        &mut self,
+        from: Square,
+        to: Square,
+        promotion: Option<PieceKind>,
+    ) -> Result<MoveKind, MoveError> {
+        let kind = self.check_move(from, to)?;
+        let promote_to = match (kind, promotion) {
+            (MoveKind::Promotion, None) => return Err(MoveError::PromotionRequired),
+            (_, promotion) => promotion.unwrap_or(PieceKind::Queen),
+        };
+        self.play(from, to, kind, promote_to);
+        return Ok(kind);
+    }
+
+    pub fn legal_moves(&self) -> Vec<(Square, Square)> {
+        let mut moves = Vec::new();
+        for i in 0..8 {
+            for j in 0..8 {
+                match self.piece_at((i, j)) {
+                    Some(p) if p.color == self.side_to_move => {}
+                    _ => continue,
+                }
+                for k in 0..8 {
+                    for l in 0..8 {
+                        if self.check_move((i, j), (k, l)).is_ok() {
+                            moves.push(((i, j), (k, l)));
+                        }
+                    }
+                }
+            }
+        }
+        return moves;
+    }
+
+    fn has_legal_move(&self) -> bool {
+        return !self.legal_moves().is_empty();
+    }
+
+    fn insufficient_material(&self) -> bool {
+        let mut minors = Vec::new();
+        for i in 0..8 {
+            for j in 0..8 {
+                match self.piece_at((i, j)) {
+                    None => {}
+                    Some(p) => match p.kind {
+                        PieceKind::King => {}
+                        PieceKind::Knight | PieceKind::Bishop => minors.push((p, (i + j) % 2)),
+                        _ => return false,
+                    },
+                }
+            }
+        }
+        match minors.as_slice() {
+            [] | [_] => return true,
+            // Bishops on the same square colour can never mate, whoever owns them.
+            [(a, sa), (b, sb)] => {
+                return a.kind == PieceKind::Bishop && b.kind == PieceKind::Bishop && sa == sb
+            }
+            _ => return false,
+        }
+    }
+
+    // A lone king or a king with a single minor piece can never force mate.
+    pub fn has_mating_material(&self, color: Color) -> bool {
+        let mut minors = 0;
+        for piece in self.squares.iter().flatten().flatten() {
+            if piece.color != color {
+                continue;
+            }
+            match piece.kind {
+                PieceKind::King => {}
+                PieceKind::Knight | PieceKind::Bishop => minors += 1,
+                _ => return true,
+            }
+        }
+        return minors > 1;
+    }
+
+    // Checks the outcomes that follow from the position alone; repetition needs the game history.
+    pub fn outcome(&self) -> Option<(GameResult, GameOverReason)> {
+        if !self.has_legal_move() {
+            if self.is_in_check(self.side_to_move) {
+                return Some((
+                    GameResult::win_for(self.side_to_move.opposite()),
+                    GameOverReason::Checkmate,
+                ));
+            }
+            return Some((GameResult::Draw, GameOverReason::Stalemate));
+        }
+        if self.insufficient_material() {
+            return Some((GameResult::Draw, GameOverReason::InsufficientMaterial));
+        }
+        if self.halfmove_clock >= 100 {
+            return Some((GameResult::Draw, GameOverReason::FiftyMoveRule));
+        }
+        return None;
+    }
+
+    pub fn position_key(&self) -> PositionKey {
+        // The en passant square only matters when the capture is actually playable.
+        let en_passant = self.en_passant.filter(|ep| {
+            let row = (ep.0 as i8 - self.side_to_move.pawn_dir()) as u8;
+            return [ep.1 as i8 - 1, ep.1 as i8 + 1].iter().any(|&col| {
+                (0..8).contains(&col)
+                    && self.piece_at((row, col as u8))
+                        == Some(Piece {
+                            kind: PieceKind::Pawn,
+                            color: self.side_to_move,
+                        })
+                    && self.check_move((row, col as u8), *ep).is_ok()
+            });
+        });
+        return PositionKey {
+            squares: self.squares,
+            side_to_move: self.side_to_move,
+            castling: self.castling,
+            en_passant,
+        };
+    }
+
+    // Standard algebraic notation for a legal move, including check and mate suffixes.
+    pub fn san(&self, from: Square, to: Square, promotion: Option<PieceKind>) -> String {
+        let kind = self.check_move(from, to).unwrap();
+        let piece = self.piece_at(from).unwrap();
+        let promote_to = promotion.unwrap_or(PieceKind::Queen);
+        let mut san = match kind {
+            MoveKind::CastleKingSide => String::from("O-O"),
+            MoveKind::CastleQueenSide => String::from("O-O-O"),
+            _ => {
+                let capture = self.piece_at(to).is_some() || kind == MoveKind::EnPassant;
+                let mut san = String::new();
+                if piece.kind == PieceKind::Pawn {
+                    if capture {
+                        san.push((b'a' + from.1) as char);
+                    }
+                } else {
+                    san.push(piece.kind.fen_char().to_ascii_uppercase());
+                    let others: Vec<Square> = self
+                        .legal_moves()
+                        .into_iter()
+                        .filter(|(f, t)| {
+                            *t == to
+                                && *f != from
+                                && self.piece_at(*f).map(|p| p.kind) == Some(piece.kind)
+                        })
+                        .map(|(f, _)| f)
+                        .collect();
+                    if !others.is_empty() {
+                        let name = square_name(from);
+                        if others.iter().all(|f| f.1 != from.1) {
+                            san.push_str(&name[..1]);
+                        } else if others.iter().all(|f| f.0 != from.0) {
+                            san.push_str(&name[1..]);
+                        } else {
+                            san.push_str(&name);
+                        }
+                    }
+                }
+                if capture {
+                    san.push('x');
+                }
+                san.push_str(&square_name(to));
+                if kind == MoveKind::Promotion {
+                    san.push('=');
+                    san.push(promote_to.fen_char().to_ascii_uppercase());
+                }
+                san
+            }
+        };
+        let mut after = self.clone();
+        after.play(from, to, kind, promote_to);
+        if after.is_in_check(after.side_to_move) {
+            san.push(if after.has_legal_move() { '+' } else { '#' });
+        }
+        return san;
+    }
+
+    fn play(&mut self, from: Square, to: Square, kind: MoveKind, promote_to: PieceKind) {
+        let piece = self.piece_at(from).unwrap();
+        let captured = self.piece_at(to);
+        self.set(to, Some(piece));
+        self.set(from, None);
+        match kind {
+            MoveKind::EnPassant => self.set((from.0, to.1), None),
+            MoveKind::CastleKingSide => {
+                let rook = self.piece_at((from.0, 7));
+                self.set((from.0, 7), None);
+                self.set((from.0, 5), rook);
+            }
+            MoveKind::CastleQueenSide => {
+                let rook = self.piece_at((from.0, 0));
+                self.set((from.0, 0), None);
+                self.set((from.0, 3), rook);
+            }
+            MoveKind::Promotion => self.set(
+                to,
+                Some(Piece {
+                    kind: promote_to,
+                    color: piece.color,
+                }),
+            ),
+            MoveKind::Normal | MoveKind::DoublePush => {}
+        }
+
+        self.en_passant = if kind == MoveKind::DoublePush {
+            Some(((from.0 + to.0) / 2, from.1))
+        } else {
+            None
+        };
+        if piece.kind == PieceKind::King {
+            self.castling.clear(piece.color, true);
+            self.castling.clear(piece.color, false);
+        }
+        for color in [Color::White, Color::Black] {
+            let row = color.home_row();
+            if from == (row, 7) || to == (row, 7) {
+                self.castling.clear(color, true);
+            }
+            if from == (row, 0) || to == (row, 0) {
+                self.castling.clear(color, false);
+            }
+        }
+
+        if piece.kind == PieceKind::Pawn || captured.is_some() {
+            self.halfmove_clock = 0;
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if piece.color == Color::Black {
+            self.fullmove_number += 1;
+        }
+        self.side_to_move = piece.color.opposite();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROMOTIONS: [PieceKind; 4] = [
+        PieceKind::Queen,
+        PieceKind::Rook,
+        PieceKind::Bishop,
+        PieceKind::Knight,
+    ];
+
+    // Counts the leaf positions `depth` plies deep; legal_moves lists a promotion once.
+    fn perft(board: &Board, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut nodes = 0;
+        for (from, to) in board.legal_moves() {
+            let promotions: &[PieceKind] = match board.check_move(from, to) {
+                Ok(MoveKind::Promotion) => &PROMOTIONS,
+                _ => &[PieceKind::Queen],
+            };
+            for promotion in promotions {
+                if depth == 1 {
+                    nodes += 1;
+                    continue;
+                }
+                let mut next = board.clone();
+                next.make_move(from, to, Some(*promotion)).unwrap();
+                nodes += perft(&next, depth - 1);
+            }
+        }
+        return nodes;
+    }
+
+    fn play(board: &mut Board, moves: &[&str]) {
+        for uci in moves {
+            let ((from, to), promotion) = parse_uci(uci).unwrap();
+            board.make_move(from, to, promotion).unwrap();
+        }
+    }
+
+    #[test]
+    fn perft_start_position() {
+        let board = Board::new();
+        assert_eq!(perft(&board, 1), 20);
+        assert_eq!(perft(&board, 2), 400);
+        assert_eq!(perft(&board, 3), 8902);
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        let board =
+            Board::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1")
+                .unwrap();
+        assert_eq!(perft(&board, 1), 48);
+        assert_eq!(perft(&board, 2), 2039);
+        assert_eq!(perft(&board, 3), 97862);
+    }
+
+    #[test]
+    fn perft_position_3() {
+        let board = Board::from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1").unwrap();
+        assert_eq!(perft(&board, 3), 2812);
+    }
+
+    #[test]
+    fn perft_position_4() {
+        let board =
+            Board::from_fen("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1")
+                .unwrap();
+        assert_eq!(perft(&board, 3), 9467);
+    }
+
+    #[test]
+    fn perft_position_5() {
+        let board =
+            Board::from_fen("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8").unwrap();
+        assert_eq!(perft(&board, 3), 62379);
+    }
+
+    #[test]
+    fn fen_validation() {
+        let error = Board::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNRR w KQkq - 0 1");
+        assert_eq!(error.err().unwrap(), "FEN rank 1 has more than 8 squares");
+        // Rights without the king and rook at home are dropped.
+        let board = Board::from_fen("r3k3/8/8/8/8/8/8/4K2R w KQkq - 0 1").unwrap();
+        assert!(board.castling.white_king_side && !board.castling.white_queen_side);
+        assert!(!board.castling.black_king_side && board.castling.black_queen_side);
+        assert_eq!(board.to_fen(), "r3k3/8/8/8/8/8/8/4K2R w Kq - 0 1");
+        // An en passant square needs the pushed pawn in front of it and an empty path.
+        assert!(Board::from_fen("4k3/8/8/3NP3/8/8/8/4K3 w - d6 0 1").is_err());
+        assert!(Board::from_fen("4k3/3n4/8/3pP3/8/8/8/4K3 w - d6 0 1").is_err());
+        let board = Board::from_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1").unwrap();
+        assert_eq!(board.en_passant, Some((2, 3)));
+    }
+
+    #[test]
+    fn checkmate() {
+        let mut board = Board::new();
+        play(&mut board, &["f2f3", "e7e5", "g2g4", "d8h4"]);
+        assert_eq!(
+            board.outcome(),
+            Some((GameResult::BlackWins, GameOverReason::Checkmate))
+        );
+    }
+
+    #[test]
+    fn stalemate() {
+        let board = Board::from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap();
+        assert_eq!(
+            board.outcome(),
+            Some((GameResult::Draw, GameOverReason::Stalemate))
+        );
+    }
+
+    #[test]
+    fn repetition_keys() {
+        let mut board = Board::new();
+        let start = board.position_key();
+        play(&mut board, &["g1f3", "g8f6", "f3g1", "f6g8"]);
+        assert!(board.position_key() == start);
+        // Castling rights are part of the position, so a king walk does not repeat it.
+        play(
+            &mut board,
+            &["e2e4", "e7e5", "e1e2", "e8e7", "e2e1", "e7e8"],
+        );
+        let mut walked = Board::new();
+        play(&mut walked, &["e2e4", "e7e5"]);
+        assert!(board.position_key() != walked.position_key());
+    }
+}
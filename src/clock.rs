@@ -0,0 +1,138 @@
+use actix::SpawnHandle;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use crate::chess::Color;
+
+// Upper bounds for a time control; anything longer is not a game this server hosts.
+const MAX_MINUTES: u64 = 180;
+const MAX_INCREMENT_SECS: u64 = 180;
+
+// Written as "minutes+increment", e.g. "5+3" is five minutes with three seconds per move.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeControl {
+    pub initial_secs: u64,
+    pub increment_secs: u64,
+}
+
+impl TryFrom<String> for TimeControl {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid time control '{}'", value);
+        let (minutes, increment) = value.trim().split_once('+').ok_or_else(invalid)?;
+        let minutes = minutes.trim().parse::<u64>().map_err(|_| invalid())?;
+        let increment = increment.trim().parse::<u64>().map_err(|_| invalid())?;
+        if minutes == 0 && increment == 0 {
+            return Err(invalid());
+        }
+        if minutes > MAX_MINUTES || increment > MAX_INCREMENT_SECS {
+            return Err(format!(
+                "Time controls are at most {}+{}",
+                MAX_MINUTES, MAX_INCREMENT_SECS
+            ));
+        }
+        return Ok(TimeControl {
+            initial_secs: minutes * 60,
+            increment_secs: increment,
+        });
+    }
+}
+
+impl From<TimeControl> for String {
+    fn from(tc: TimeControl) -> String {
+        return format!("{}+{}", tc.initial_secs / 60, tc.increment_secs);
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct ClockTimes {
+    pub white: u64,
+    pub black: u64,
+}
+
+#[derive(Clone)]
+pub struct Clock {
+    pub time_control: TimeControl,
+    white_ms: u64,
+    black_ms: u64,
+    // Whose clock is running and since when.
+    running: Option<(Color, Instant)>,
+    pub flag_timer: Option<SpawnHandle>,
+}
+
+impl Clock {
+    pub fn new(time_control: TimeControl) -> Clock {
+        let initial = time_control.initial_secs.saturating_mul(1000);
+        return Clock {
+            time_control,
+            white_ms: initial,
+            black_ms: initial,
+            running: None,
+            flag_timer: None,
+        };
+    }
+
+    fn stored(&mut self, color: Color) -> &mut u64 {
+        match color {
+            Color::White => return &mut self.white_ms,
+            Color::Black => return &mut self.black_ms,
+        }
+    }
+
+    // Remaining milliseconds for `color`, counting the time used on a running clock.
+    pub fn remaining(&self, color: Color) -> u64 {
+        let stored = match color {
+            Color::White => self.white_ms,
+            Color::Black => self.black_ms,
+        };
+        match self.running {
+            Some((running, since)) if running == color => {
+                return stored.saturating_sub(since.elapsed().as_millis() as u64)
+            }
+            _ => return stored,
+        }
+    }
+
+    pub fn times(&self) -> ClockTimes {
+        return ClockTimes {
+            white: self.remaining(Color::White),
+            black: self.remaining(Color::Black),
+        };
+    }
+
+    pub fn running_side(&self) -> Option<Color> {
+        return self.running.map(|(color, _)| color);
+    }
+
+    pub fn flagged(&self) -> Option<Color> {
+        return self
+            .running_side()
+            .filter(|color| self.remaining(*color) == 0);
+    }
+
+    // Charges the mover for their thinking time, adds the increment and starts the opponent.
+    // The clock starts running with the first move, so white's first move is free.
+    pub fn press(&mut self, mover: Color) {
+        self.stop();
+        let increment = self.time_control.increment_secs.saturating_mul(1000);
+        let stored = self.stored(mover);
+        *stored = stored.saturating_add(increment);
+        self.running = Some((mover.opposite(), Instant::now()));
+    }
+
+    // Puts the clock back to an earlier reading, restarting `running` from now.
+    pub fn restore(&mut self, times: ClockTimes, running: Option<Color>) {
+        self.white_ms = times.white;
+        self.black_ms = times.black;
+        self.running = running.map(|color| (color, Instant::now()));
+    }
+
+    pub fn stop(&mut self) {
+        if let Some((color, _)) = self.running {
+            let remaining = self.remaining(color);
+            *self.stored(color) = remaining;
+            self.running = None;
+        }
+    }
+}
@@ -1,53 +1,294 @@
-use std::sync::Mutex;
+#![allow(
+    clippy::needless_return,
+    clippy::upper_case_acronyms,
+    clippy::inherent_to_string
+)]
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use actix::{Actor, Addr};
 use actix_cors::Cors;
-use actix_web::{get, http, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{get, http, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+mod auth;
+mod chess;
+mod clock;
+mod pgn;
+mod rating;
+mod room_code;
 mod socket;
+mod storage;
 use once_cell::sync::Lazy;
 use socket::Socket;
 
-use crate::socket::Server;
+use crate::auth::User;
+use crate::room_code::RoomCode;
+use crate::socket::{
+    CreateUser, FindRoom, FindUser, GetChallenges, GetPgn, GetRoomInfo, Protocol, Server, Viewer,
+};
+use crate::storage::{MemoryStorage, SharedStorage, SqliteStorage};
 
 #[get("/")]
 async fn test() -> impl Responder {
     HttpResponse::Ok().body("Hello")
 }
 
+// Games are kept in the SQLite file named by CHESS_DB, chess.db by default.
+fn open_storage() -> SharedStorage {
+    let path = std::env::var("CHESS_DB").unwrap_or(String::from("chess.db"));
+    match SqliteStorage::open(&path) {
+        Ok(storage) => return Arc::new(Mutex::new(storage)),
+        Err(err) => {
+            println!(
+                "Cannot open {}: {}; games will not survive a restart",
+                path, err
+            );
+            return Arc::new(Mutex::new(MemoryStorage::default()));
+        }
+    }
+}
+
 static SERVER: Lazy<Mutex<Addr<Server>>> = Lazy::new(|| {
     Mutex::new(
         (Server {
             addr: None,
-            rooms: Vec::new(),
+            rooms: HashMap::new(),
+            seeks: HashMap::new(),
+            storage: open_storage(),
+            writer: None,
+            lobby: Vec::new(),
+            challenges: Vec::new(),
+            arbiters: Vec::new(),
+            next_arbiter: 0,
         })
         .start(),
     )
 });
 
+#[derive(Deserialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct LoginReply {
+    id: i64,
+    name: String,
+    token: String,
+}
+
+fn login_reply(user: User) -> HttpResponse {
+    return HttpResponse::Ok().json(LoginReply {
+        token: auth::issue_token(&user),
+        id: user.id,
+        name: user.name,
+    });
+}
+
+#[post("/register")]
+async fn register(credentials: web::Json<Credentials>) -> impl Responder {
+    let Credentials { name, password } = credentials.into_inner();
+    if let Err(reason) = auth::validate_name(&name).and(auth::validate_password(&password)) {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    let password_hash = match web::block(move || auth::hash_password(&password)).await {
+        Ok(Ok(hash)) => hash,
+        _ => return HttpResponse::InternalServerError().body("Cannot hash password"),
+    };
+    let server = SERVER.lock().unwrap().to_owned();
+    let created = server
+        .send(CreateUser {
+            name: name.clone(),
+            password_hash,
+        })
+        .await;
+    match created {
+        Ok(Ok(Some(id))) => return login_reply(User { id, name }),
+        Ok(Ok(None)) => return HttpResponse::Conflict().body("Name already taken"),
+        _ => return HttpResponse::InternalServerError().body("Cannot create account"),
+    }
+}
+
+#[post("/login")]
+async fn login(credentials: web::Json<Credentials>) -> impl Responder {
+    let Credentials { name, password } = credentials.into_inner();
+    let server = SERVER.lock().unwrap().to_owned();
+    let user = match server.send(FindUser(name)).await {
+        Ok(Ok(user)) => user,
+        _ => return HttpResponse::InternalServerError().body("Cannot look up account"),
+    };
+    // Unknown names are hashed against nothing so they take as long as wrong passwords.
+    let verified = web::block(move || match user {
+        Some(user) if auth::verify_password(&password, &user.password_hash) => Some(User {
+            id: user.id,
+            name: user.name,
+        }),
+        Some(_) => None,
+        None => {
+            let _ = auth::hash_password(&password);
+            None
+        }
+    })
+    .await;
+    match verified {
+        Ok(Some(user)) => return login_reply(user),
+        Ok(None) => return HttpResponse::Unauthorized().body("Wrong name or password"),
+        Err(_) => return HttpResponse::InternalServerError().body("Cannot check password"),
+    }
+}
+
+// Browsers cannot set headers on a WebSocket, so the session token may also come as
+// the `token` query parameter.
+fn session_token(req: &HttpRequest) -> Option<String> {
+    let header = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    if let Some(token) = header {
+        return Some(String::from(token));
+    }
+    return web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("token").cloned());
+}
+
 #[get("/ws")]
 async fn get_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
-    // let
-
-    let resp = ws::start(
-        Socket {
-            id: String::from("0"),
-            addr: None,
-            name: String::from(""),
-            server: SERVER.lock().unwrap().to_owned(),
+    // Connections without a token play as guests; a bad token is refused outright.
+    let user = match session_token(&req) {
+        Some(token) => match auth::verify_token(&token) {
+            Ok(user) => Some(user),
+            Err(reason) => return Ok(HttpResponse::Unauthorized().body(reason)),
         },
-        &req,
-        stream,
-    );
+        None => None,
+    };
+    // Clients that ask for no subprotocol get v1.
+    let protocol = req
+        .headers()
+        .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|header| header.to_str().ok())
+        .and_then(Protocol::negotiate);
+    let socket = Socket {
+        id: String::from("0"),
+        token: String::new(),
+        addr: None,
+        name: user
+            .as_ref()
+            .map(|user| user.name.clone())
+            .unwrap_or_default(),
+        server: SERVER.lock().unwrap().to_owned(),
+        rooms: HashMap::new(),
+        window_start: Instant::now(),
+        window_count: 0,
+        protocol: protocol.unwrap_or(Protocol::V1),
+        user_id: user.map(|user| user.id),
+    };
+    let builder = ws::WsResponseBuilder::new(socket, &req, stream);
+    let resp = match protocol {
+        Some(protocol) => builder.protocols(&[protocol.name()]).start(),
+        None => builder.start(),
+    };
     // println!("{:?}", resp);
     resp
 }
 
+// Who is asking, for rooms that are private: the session token if any, and the room
+// password as the `password` query parameter.
+fn viewer(req: &HttpRequest) -> Result<Viewer, HttpResponse> {
+    let user = match session_token(req) {
+        Some(token) => match auth::verify_token(&token) {
+            Ok(user) => Some(user),
+            Err(reason) => return Err(HttpResponse::Unauthorized().body(reason)),
+        },
+        None => None,
+    };
+    let password = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("password").cloned());
+    return Ok(Viewer {
+        name: user
+            .as_ref()
+            .map(|user| user.name.clone())
+            .unwrap_or_default(),
+        user_id: user.map(|user| user.id),
+        password,
+    });
+}
+
+#[get("/games/{code}/pgn")]
+async fn get_pgn(req: HttpRequest, code: web::Path<String>) -> impl Responder {
+    let viewer = match viewer(&req) {
+        Ok(viewer) => viewer,
+        Err(response) => return response,
+    };
+    let code = match RoomCode::try_from(code.into_inner()) {
+        Ok(code) => code,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let server = SERVER.lock().unwrap().to_owned();
+    let room = match server.send(FindRoom(code)).await {
+        Ok(Some(room)) => room,
+        _ => return HttpResponse::NotFound().body("No room found"),
+    };
+    match room.send(GetPgn(viewer)).await {
+        Ok(Ok(pgn)) => HttpResponse::Ok()
+            .content_type("application/x-chess-pgn")
+            .body(pgn),
+        Ok(Err(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(_) => HttpResponse::NotFound().body("No room found"),
+    }
+}
+
+// Invite links point here: whether the room exists, who is in it and how far along it
+// is. Joining still happens over the socket with ConnectWith or Spectate. Private rooms
+// answer 403 until the password or an invited session comes along.
+#[get("/join/{code}")]
+async fn join_room(req: HttpRequest, code: web::Path<String>) -> impl Responder {
+    let viewer = match viewer(&req) {
+        Ok(viewer) => viewer,
+        Err(response) => return response,
+    };
+    let code = match RoomCode::try_from(code.into_inner()) {
+        Ok(code) => code,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let server = SERVER.lock().unwrap().to_owned();
+    let room = match server.send(FindRoom(code)).await {
+        Ok(Some(room)) => room,
+        _ => return HttpResponse::NotFound().body("No room found"),
+    };
+    match room.send(GetRoomInfo(viewer)).await {
+        Ok(Ok(info)) => HttpResponse::Ok().json(info),
+        Ok(Err(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(_) => HttpResponse::NotFound().body("No room found"),
+    }
+}
+
+// Open public challenges; the same list lobby subscribers get over the socket.
+#[get("/lobby")]
+async fn get_lobby() -> impl Responder {
+    let server = SERVER.lock().unwrap().to_owned();
+    match server.send(GetChallenges).await {
+        Ok(challenges) => HttpResponse::Ok().json(challenges),
+        Err(_) => HttpResponse::InternalServerError().body("Lobby unavailable"),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
     // let server = server::ChessServer::new().start();
+    // Start the server actor now so stored games are restored before anyone connects.
+    Lazy::force(&SERVER);
     let server_addr = "127.0.0.1";
-    let server_port = 8080;
+    // CHESS_PORT lets the benchmark run its own server next to a dev one.
+    let server_port = std::env::var("CHESS_PORT")
+        .ok()
+        .and_then(|port| port.parse::<u16>().ok())
+        .unwrap_or(8080);
     let app = HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://localhost:5173")
@@ -57,7 +298,15 @@ async fn main() -> Result<(), std::io::Error> {
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
             .max_age(3600);
-        App::new().wrap(cors).service(test).service(get_ws)
+        App::new()
+            .wrap(cors)
+            .service(test)
+            .service(get_ws)
+            .service(get_pgn)
+            .service(join_room)
+            .service(get_lobby)
+            .service(register)
+            .service(login)
     })
     .bind((server_addr, server_port))?
     .run();
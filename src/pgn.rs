@@ -0,0 +1,93 @@
+use crate::chess::{Board, Color};
+
+pub struct PgnGame<'a> {
+    pub white: &'a str,
+    pub black: &'a str,
+    pub result: &'a str,
+    // Milliseconds since the unix epoch.
+    pub started_at: u64,
+    pub start_fen: &'a str,
+    pub moves: Vec<&'a str>,
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) civil date.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+fn tag(name: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    return format!("[{} \"{}\"]\n", name, value);
+}
+
+fn player_name(name: &str) -> &str {
+    if name.trim().is_empty() {
+        return "?";
+    }
+    return name;
+}
+
+pub fn write_pgn(game: &PgnGame) -> String {
+    let (year, month, day) = civil_date((game.started_at / 86_400_000) as i64);
+    let mut pgn = String::new();
+    pgn.push_str(&tag("Event", "Casual game"));
+    pgn.push_str(&tag("Site", "chess-server"));
+    pgn.push_str(&tag(
+        "Date",
+        &format!("{:04}.{:02}.{:02}", year, month, day),
+    ));
+    pgn.push_str(&tag("Round", "-"));
+    pgn.push_str(&tag("White", player_name(game.white)));
+    pgn.push_str(&tag("Black", player_name(game.black)));
+    pgn.push_str(&tag("Result", game.result));
+
+    let start = Board::from_fen(game.start_fen).unwrap();
+    if start.to_fen() != Board::new().to_fen() {
+        pgn.push_str(&tag("SetUp", "1"));
+        pgn.push_str(&tag("FEN", game.start_fen));
+    }
+    pgn.push('\n');
+
+    let mut tokens = Vec::new();
+    let mut number = start.fullmove_number;
+    let mut side = start.side_to_move;
+    if side == Color::Black && !game.moves.is_empty() {
+        tokens.push(format!("{}...", number));
+    }
+    for san in game.moves.iter() {
+        if side == Color::White {
+            tokens.push(format!("{}.", number));
+        } else {
+            number += 1;
+        }
+        tokens.push(san.to_string());
+        side = side.opposite();
+    }
+    tokens.push(game.result.to_string());
+
+    // Movetext lines are kept under 80 characters.
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > 79 {
+            pgn.push_str(&line);
+            pgn.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&token);
+    }
+    pgn.push_str(&line);
+    pgn.push('\n');
+    return pgn;
+}
@@ -0,0 +1,153 @@
+use serde::Serialize;
+
+use crate::clock::TimeControl;
+
+// Converts between the Glicko scale and the Glicko-2 internal scale.
+const SCALE: f64 = 173.7178;
+// Constrains how fast volatility changes; the Glicko-2 paper suggests 0.3 to 1.2.
+const TAU: f64 = 0.5;
+const CONVERGENCE: f64 = 0.000001;
+
+// Ratings are kept apart per speed, picked by the expected length of a 40 move game.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize)]
+pub enum Category {
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+}
+
+impl Category {
+    pub fn of(time_control: TimeControl) -> Category {
+        let estimate = time_control
+            .initial_secs
+            .saturating_add(time_control.increment_secs.saturating_mul(40));
+        if estimate < 180 {
+            return Category::Bullet;
+        } else if estimate < 480 {
+            return Category::Blitz;
+        } else if estimate < 1500 {
+            return Category::Rapid;
+        }
+        return Category::Classical;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Rating {
+        return Rating {
+            rating: 1500.0,
+            deviation: 350.0,
+            volatility: 0.06,
+        };
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    return 1.0 / (1.0 + 3.0 * phi * phi / (std::f64::consts::PI * std::f64::consts::PI)).sqrt();
+}
+
+fn expected(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
+    return 1.0 / (1.0 + (-g(phi_j) * (mu - mu_j)).exp());
+}
+
+impl Rating {
+    // Glicko-2 update for one rating period. `results` pairs each opponent's rating from
+    // before the period with the score against them: 1 for a win, 0.5 a draw, 0 a loss.
+    pub fn update(&self, results: &[(Rating, f64)]) -> Rating {
+        let mu = (self.rating - 1500.0) / SCALE;
+        let phi = self.deviation / SCALE;
+        if results.is_empty() {
+            let phi = (phi * phi + self.volatility * self.volatility).sqrt();
+            return Rating {
+                deviation: phi * SCALE,
+                ..*self
+            };
+        }
+
+        let mut v_inv = 0.0;
+        let mut improvement = 0.0;
+        for (opponent, score) in results {
+            let mu_j = (opponent.rating - 1500.0) / SCALE;
+            let phi_j = opponent.deviation / SCALE;
+            let e = expected(mu, mu_j, phi_j);
+            v_inv += g(phi_j) * g(phi_j) * e * (1.0 - e);
+            improvement += g(phi_j) * (score - e);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * improvement;
+
+        // New volatility: the root of f, found with the Illinois method.
+        let a = (self.volatility * self.volatility).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            return ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d)
+                - (x - a) / (TAU * TAU);
+        };
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * TAU) < 0.0 {
+                k += 1.0;
+            }
+            a - k * TAU
+        };
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > CONVERGENCE {
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b < 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+        let volatility = (big_a / 2.0).exp();
+
+        let phi_star = (phi * phi + volatility * volatility).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * improvement;
+        return Rating {
+            rating: new_mu * SCALE + 1500.0,
+            deviation: new_phi * SCALE,
+            volatility,
+        };
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct RatingChange {
+    pub rating: i32,
+    pub change: i32,
+    pub deviation: i32,
+}
+
+impl RatingChange {
+    pub fn new(before: Rating, after: Rating) -> RatingChange {
+        return RatingChange {
+            rating: after.rating.round() as i32,
+            change: (after.rating.round() - before.rating.round()) as i32,
+            deviation: after.deviation.round() as i32,
+        };
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct RatingChanges {
+    pub white: RatingChange,
+    pub black: RatingChange,
+}
@@ -0,0 +1,56 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Crockford's base32 leaves out I, L, O and U, so codes survive being read aloud.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// Ten characters carry 50 random bits; guessing a live room is hopeless.
+const LENGTH: usize = 10;
+
+// Written as two groups of five, e.g. "7K2QM-X9HC4". Parsing ignores case, dashes and
+// spaces, and reads O as 0 and I or L as 1, as Crockford suggests.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RoomCode(String);
+
+impl RoomCode {
+    // thread_rng is a CSPRNG, so one code says nothing about the next.
+    pub fn random() -> RoomCode {
+        let mut rng = rand::thread_rng();
+        let chars: String = (0..LENGTH)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect();
+        return RoomCode(format!("{}-{}", &chars[..5], &chars[5..]));
+    }
+}
+
+impl TryFrom<String> for RoomCode {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let chars: String = value
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .map(|c| match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            })
+            .collect();
+        if chars.len() != LENGTH || !chars.bytes().all(|c| ALPHABET.contains(&c)) {
+            return Err(format!("Invalid room code '{}'", value));
+        }
+        return Ok(RoomCode(format!("{}-{}", &chars[..5], &chars[5..])));
+    }
+}
+
+impl From<RoomCode> for String {
+    fn from(code: RoomCode) -> String {
+        return code.0;
+    }
+}
+
+impl fmt::Display for RoomCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.0);
+    }
+}
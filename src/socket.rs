@@ -1,198 +1,892 @@
-use actix::{dev::MessageResponse, Actor, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix::{
+    dev::MessageResponse, Actor, ActorContext, ActorFutureExt, Addr, Arbiter, ArbiterHandle,
+    AsyncContext, Handler, Message, MessageResult, SpawnHandle, StreamHandler, WrapFuture,
+};
 use actix_web_actors::ws;
-use rand::{self, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+use crate::auth::Access;
+use crate::chess::{
+    self, Board, Color, GameOverReason, GameResult, MoveKind, PieceKind, PositionKey, Square,
+};
+use crate::clock::{Clock, ClockTimes, TimeControl};
+use crate::pgn::{self, PgnGame};
+use crate::rating::{Category, Rating, RatingChange, RatingChanges};
+use crate::room_code::RoomCode;
+use crate::storage::{
+    ActiveGame, GameKey, MoveRow, Persist, PlayerRecord, RoomRecord, SharedStorage, Storage,
+    UserRecord, Write, Writer,
+};
+
+// Commands that are not about one particular room.
+enum ServerCommands {
+    AddRoom(Socket, RoomOptions),
+    // The socket closed; drops its seek and lobby subscription.
+    Disconnect(Addr<Socket>),
+    Seek(Socket, SeekOptions),
+    CancelSeek(Addr<Socket>, String),
+    SubscribeLobby(Addr<Socket>),
+    UnsubscribeLobby(Addr<Socket>),
+}
+
+// Commands for a single game, handled by that room's actor.
+enum RoomCommands {
+    // The player and the room password they gave.
+    AddPlayer(Socket, Option<String>),
+    OppReady,
+    Move((u8, u8), (u8, u8), Option<String>),
+    Promote((u8, u8), String),
+    // The observer and the room password they gave.
+    Spectate(Socket, Option<String>),
+    PromoteReq,
+    Fen,
+    Rejoin(Socket, String),
+    // The socket closed; sent by it to every room it had joined.
+    Disconnect,
+    Resign,
+    OfferDraw,
+    AcceptDraw,
+    DeclineDraw,
+    Abort,
+    TakebackRequest,
+    TakebackAccept,
+    Sync,
+    RematchOffer,
+    RematchAccept,
+}
+
+impl RoomCommands {
+    // Commands that may leave the socket seated or watching, so it must be told about
+    // the room when it disconnects.
+    fn joins(&self) -> bool {
+        return matches!(
+            self,
+            RoomCommands::AddPlayer(..) | RoomCommands::Spectate(..) | RoomCommands::Rejoin(..)
+        );
+    }
+}
+
+// A room command from a socket, sent straight to the room's actor.
 #[derive(Message)]
 #[rtype(result = "()")]
+struct RoomRequest {
+    addr: Addr<Socket>,
+    sckt_id: String,
+    command: RoomCommands,
+    // The client message it came from, echoed back in error replies.
+    request: Value,
+}
 
-enum ServerCommands {
-    AddRoom(Socket),
-    AddPlayerToRoom(Socket, u16),
-    OppReady(Socket, u16),
-    Move(Addr<Socket>, String, u16, (u8, u8), (u8, u8)),
-    Promote(u16, String, (u8, u8), String),
+// What a room tells the Server about itself.
+#[derive(Message)]
+#[rtype(result = "()")]
+enum RoomUpdate {
+    // The room's lobby entry, None once it should no longer be listed.
+    Challenge(RoomCode, Option<Challenge>),
+    // The room actor stopped; its code may be handed out again.
+    Closed(RoomCode),
 }
 
+// How long a player who dropped mid-game has to rejoin before the game is decided.
+const DISCONNECT_GRACE_SECS: u64 = 60;
+
+// Keeps track of the rooms, each its own actor, and of everything that spans rooms:
+// matchmaking, the lobby and handing out room codes. Sockets only ask it where a room
+// is; room commands go to the room directly.
 pub struct Server {
-    pub rooms: Vec<Room>,
+    pub rooms: HashMap<RoomCode, Addr<Room>>,
     pub addr: Option<Addr<Server>>,
+    // Players waiting for an opponent, oldest first, pooled by time control.
+    pub seeks: HashMap<TimeControl, Vec<Seeker>>,
+    pub storage: SharedStorage,
+    // Stores the games; started with the Server, on a thread of its own.
+    pub writer: Option<Addr<Writer>>,
+    // Sockets following the lobby, and the open challenges in the order they were made.
+    pub lobby: Vec<Addr<Socket>>,
+    pub challenges: Vec<Challenge>,
+    // Threads the room actors are spread over, one per core.
+    pub arbiters: Vec<ArbiterHandle>,
+    pub next_arbiter: usize,
+}
+
+// Ratings are keyed by account name; only logged in players can play rated games.
+// Players without a rated game yet start from the Glicko-2 default.
+fn rating_of(storage: &dyn Storage, name: &str, category: Category) -> Rating {
+    match storage.rating(name, category) {
+        Ok(rating) => return rating.unwrap_or_default(),
+        Err(err) => {
+            println!("storage: {}", err);
+            return Rating::default();
+        }
+    }
+}
+
+// Guests may not take a registered player's name, nor play rated games.
+fn check_identity(storage: &dyn Storage, socket: &Socket, rated: bool) -> Result<(), String> {
+    if socket.user_id.is_some() {
+        return Ok(());
+    }
+    if rated {
+        return Err(String::from("Log in to play rated games"));
+    }
+    if let Ok(Some(_)) = storage.user(&socket.name) {
+        return Err(String::from("That name belongs to a registered player"));
+    }
+    return Ok(());
+}
+
+// A command from a socket together with the raw message it came from.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ClientRequest {
+    command: ServerCommands,
+    // Echoed back in error replies.
+    request: Value,
+}
+
+impl Handler<RoomUpdate> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: RoomUpdate, _ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            RoomUpdate::Challenge(code, challenge) => self.list_challenge(code, challenge),
+            RoomUpdate::Closed(code) => {
+                self.list_challenge(code.clone(), None);
+                self.rooms.remove(&code);
+            }
+        }
+    }
 }
 
 impl Server {
-    fn find_room(&mut self, key: u16) -> Option<&mut Room> {
-        for room in self.rooms.iter_mut() {
-            if room.id == key {
-                return Some(room);
+    // Runs a room on the next arbiter in turn and registers it under its code.
+    fn start_room(&mut self, room: Room) -> Addr<Room> {
+        let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+        self.next_arbiter += 1;
+        let code = room.id.clone();
+        let addr = Room::start_in_arbiter(arbiter, |_ctx| room);
+        self.rooms.insert(code, addr.clone());
+        return addr;
+    }
+    // Updates the lobby entry for a room and tells subscribers what changed.
+    fn list_challenge(&mut self, code: RoomCode, challenge: Option<Challenge>) {
+        if let Some(index) = self
+            .challenges
+            .iter()
+            .position(|listed| listed.room_code == code)
+        {
+            self.challenges.remove(index);
+            for addr in self.lobby.iter() {
+                addr.do_send(MSG::init(ServerMsg::ChallengeRemoved(code.clone())));
             }
         }
-        return None;
+        if let Some(challenge) = challenge {
+            for addr in self.lobby.iter() {
+                addr.do_send(MSG::init(ServerMsg::ChallengeCreated(challenge.clone())));
+            }
+            self.challenges.push(challenge);
+        }
+    }
+    // Brings back the games that were in progress when the server last stopped. Their
+    // players count as away until they Rejoin with their session token.
+    fn load_games(&mut self, ctx: &mut actix::Context<Self>) {
+        let games = match self.storage.lock().unwrap().active_games() {
+            Ok(games) => games,
+            Err(err) => {
+                println!("storage: {}", err);
+                return;
+            }
+        };
+        for game in games {
+            let id = game.id;
+            match Room::restore(game, ctx.address(), self.storage.clone(), self.writer()) {
+                Ok(room) => {
+                    self.start_room(room);
+                }
+                Err(err) => println!("storage: cannot restore room {}: {}", id, err),
+            }
+        }
+        println!("Restored {} games", self.rooms.len());
+    }
+    fn writer(&self) -> Addr<Writer> {
+        return self.writer.clone().unwrap();
+    }
+    fn new_room_code(&mut self) -> RoomCode {
+        let mut room_code = RoomCode::random();
+        while self.rooms.contains_key(&room_code) {
+            room_code = RoomCode::random();
+        }
+        return room_code;
+    }
+    fn cancel_seek(&mut self, addr: &Addr<Socket>) -> bool {
+        let mut found = false;
+        for pool in self.seeks.values_mut() {
+            let before = pool.len();
+            pool.retain(|seeker| seeker.socket.addr.as_ref() != Some(addr));
+            found |= pool.len() != before;
+        }
+        self.seeks.retain(|_, pool| !pool.is_empty());
+        return found;
+    }
+    // Seats two matched seekers in a new room; whoever waited longer plays white.
+    fn start_matched_game(&mut self, white: Seeker, black: Seeker) {
+        let room_code = self.new_room_code();
+        let white_id = white.socket.id.clone();
+        let black_id = black.socket.id.clone();
+        let white_addr = white.socket.addr.clone().unwrap();
+        let black_addr = black.socket.addr.clone().unwrap();
+        let mut room = Room::init(
+            room_code.clone(),
+            white.socket,
+            Some(black.socket),
+            white_id.clone(),
+            Board::new(),
+            Some(Clock::new(white.options.time_control)),
+            false,
+            self.addr.clone().unwrap(),
+            self.storage.clone(),
+            self.writer(),
+        );
+        room.rated = white.options.rated;
+        for id in [white_id, black_id] {
+            let state = room.state(&id);
+            room.send_to(&id, ServerMsg::Matched(state));
+        }
+        let room = self.start_room(room);
+        white_addr.do_send(Seated(room_code.clone(), room.clone()));
+        black_addr.do_send(Seated(room_code.clone(), room));
+        println!("matched roomcode = {}", room_code);
     }
 }
 
-impl Handler<ServerCommands> for Server {
+impl Handler<ClientRequest> for Server {
     type Result = ();
-    fn handle(&mut self, msg: ServerCommands, _ctx: &mut Self::Context) -> Self::Result {
-        match msg {
-            ServerCommands::AddRoom(p1_socket) => {
-                let mut rng = rand::thread_rng();
-                let mut room_code = rng.gen::<u16>();
-                while let Some(_room) = self.find_room(room_code) {
-                    room_code = rng.gen::<u16>();
-                }
-                let room = Room::init(room_code, p1_socket.clone(), None, p1_socket.clone().id);
-                self.rooms.push(room);
-                #[derive(Serialize)]
-                struct IdAndCode {
-                    id: String,
-                    code: String,
-                }
-                p1_socket.clone().addr.unwrap().do_send(MSG::init(
-                    EventOrError::Event(Event::GetCode),
-                    &serde_json::to_string(&IdAndCode {
-                        id: p1_socket.id,
-                        code: room_code.to_string(),
-                    })
-                    .unwrap(),
-                ));
+    fn handle(&mut self, msg: ClientRequest, _ctx: &mut Self::Context) -> Self::Result {
+        let ClientRequest { command, request } = msg;
+        match command {
+            ServerCommands::AddRoom(p1_socket, options) => {
+                let board = match options.fen {
+                    Some(fen) => match Board::from_fen(&fen) {
+                        Ok(board) => board,
+                        Err(reason) => {
+                            p1_socket.addr.unwrap().do_send(MSG::error(
+                                EventError::InvalidFen,
+                                &reason,
+                                &request,
+                            ));
+                            return;
+                        }
+                    },
+                    None => Board::new(),
+                };
+                // A position that is already decided would never send GameOver.
+                if board.outcome().is_some() {
+                    p1_socket.addr.unwrap().do_send(MSG::error(
+                        EventError::InvalidFen,
+                        "The game is already over in this position",
+                        &request,
+                    ));
+                    return;
+                }
+                let standard_start = board.to_fen() == Board::new().to_fen();
+                if options.rated
+                    && (options.time_control.is_none() || options.casual || !standard_start)
+                {
+                    p1_socket.addr.unwrap().do_send(MSG::error(
+                        EventError::NotAllowed,
+                        "Rated games need a time control, the standard start and no takebacks",
+                        &request,
+                    ));
+                    return;
+                }
+                let identity =
+                    check_identity(&*self.storage.lock().unwrap(), &p1_socket, options.rated);
+                if let Err(reason) = identity {
+                    p1_socket.addr.unwrap().do_send(MSG::error(
+                        EventError::NotAllowed,
+                        &reason,
+                        &request,
+                    ));
+                    return;
+                }
+                let room_code = self.new_room_code();
+                let mut room = Room::init(
+                    room_code.clone(),
+                    p1_socket.clone(),
+                    None,
+                    p1_socket.clone().id,
+                    board,
+                    options.time_control.map(Clock::new),
+                    options.casual,
+                    self.addr.clone().unwrap(),
+                    self.storage.clone(),
+                    self.writer(),
+                );
+                room.rated = options.rated;
+                room.public = options.public;
+                room.access = Access::new(
+                    options.password.as_deref(),
+                    options.invitee,
+                    options.allowed_users,
+                );
+                room.creator_color = options.color.resolve();
+                room.update_turn();
+                let room = self.start_room(room);
+                let addr = p1_socket.addr.clone().unwrap();
+                addr.do_send(Seated(room_code.clone(), room));
+                addr.do_send(MSG::init(ServerMsg::GetCode(IdAndCode {
+                    id: p1_socket.id,
+                    code: room_code.clone(),
+                })));
                 println!("roomcode = {}", room_code);
             }
-            ServerCommands::AddPlayerToRoom(p2_socket, room_id) => {
-                let room = &mut self.find_room(room_id);
-                if let Some(room) = room {
-                    println!("Here room");
-                    room.display();
-                    if let Some(_) = room.sockets.1 {
-                        p2_socket.clone().addr.unwrap().do_send(MSG::init(
-                            EventOrError::EventError(EventError::RoomFull),
-                            &String::from("Room Full"),
-                        ));
-                    } else {
-                        room.add_player(p2_socket.clone());
-                        let msg =
-                            MSG::init(EventOrError::Event(Event::ConnectWith), &p2_socket.name);
-                        room.sockets.clone().0.addr.unwrap().do_send(msg);
-                        p2_socket.addr.unwrap().do_send(MSG::init(
-                            EventOrError::Event(Event::ConnectWith),
-                            &room.sockets.clone().0.name,
-                        ));
+
+            ServerCommands::Disconnect(addr) => {
+                self.cancel_seek(&addr);
+                self.lobby.retain(|a| a != &addr);
+            }
+
+            ServerCommands::Seek(sckt, options) => {
+                let addr = sckt.addr.clone().unwrap();
+                let storage = self.storage.lock().unwrap();
+                if let Err(reason) = check_identity(&*storage, &sckt, options.rated) {
+                    addr.do_send(MSG::error(EventError::NotAllowed, &reason, &request));
+                    return;
+                }
+                let category = Category::of(options.time_control);
+                let rating = rating_of(&*storage, &sckt.name, category);
+                drop(storage);
+                // A new seek replaces any earlier one from the same socket.
+                self.cancel_seek(&addr);
+                let seeker = Seeker {
+                    rating: rating.rating.round() as u32,
+                    socket: sckt,
+                    options,
+                };
+                let pool = self.seeks.entry(seeker.options.time_control).or_default();
+                let opponent = pool
+                    .iter()
+                    .position(|other| other.accepts(&seeker) && seeker.accepts(other));
+                match opponent {
+                    Some(index) => {
+                        let opponent = pool.remove(index);
+                        self.seeks.retain(|_, pool| !pool.is_empty());
+                        self.start_matched_game(opponent, seeker);
                     }
+                    None => {
+                        addr.do_send(MSG::init(ServerMsg::Seeking(seeker.options.clone())));
+                        pool.push(seeker);
+                    }
+                }
+            }
+
+            ServerCommands::CancelSeek(addr, _sckt_id) => {
+                if self.cancel_seek(&addr) {
+                    addr.do_send(MSG::init(ServerMsg::SeekCancelled));
                 } else {
-                    let msg = MSG::init(
-                        EventOrError::EventError(EventError::RoomFull),
-                        &String::from("No room found"),
-                    );
-                    p2_socket.addr.unwrap().do_send(msg);
+                    addr.do_send(MSG::error(
+                        EventError::NoPendingRequest,
+                        &String::from("Not seeking a game"),
+                        &request,
+                    ));
                 }
             }
-            ServerCommands::OppReady(sckt, code) => {
-                let room = &mut self.find_room(code);
-                if let Some(room) = room {
-                    if let Some(pl2_socket) = room.sockets.1.clone() {
-                        if sckt.id == pl2_socket.id {
-                            room.get_pl1_addr()
-                                .do_send(MSG::init(EventOrError::Event(Event::OppReady), &sckt.id));
-                        } else {
-                            room.get_pl2_addr().do_send(MSG::init(
-                                EventOrError::Event(Event::OppReady),
-                                &room.sockets.0.id,
-                            ));
-                        }
+
+            ServerCommands::SubscribeLobby(addr) => {
+                if !self.lobby.contains(&addr) {
+                    self.lobby.push(addr.clone());
+                }
+                addr.do_send(MSG::init(ServerMsg::Lobby(self.challenges.clone())));
+            }
+
+            ServerCommands::UnsubscribeLobby(addr) => {
+                self.lobby.retain(|a| a != &addr);
+            }
+        }
+    }
+}
+
+impl Handler<RoomRequest> for Room {
+    type Result = ();
+    fn handle(&mut self, msg: RoomRequest, ctx: &mut Self::Context) -> Self::Result {
+        let RoomRequest {
+            addr,
+            sckt_id,
+            command,
+            request,
+            ..
+        } = msg;
+        match command {
+            RoomCommands::AddPlayer(p2_socket, password) => {
+                let identity =
+                    check_identity(&*self.storage.lock().unwrap(), &p2_socket, self.rated)
+                        .and_then(|_| {
+                            self.access.check(
+                                &p2_socket.name,
+                                p2_socket.user_id,
+                                password.as_deref(),
+                            )
+                        });
+                println!("Here room");
+                self.display();
+                if let Err(reason) = identity {
+                    addr.do_send(MSG::error(EventError::NotAllowed, &reason, &request));
+                } else if self.sockets.0.same_player(&p2_socket) {
+                    addr.do_send(MSG::error(
+                        EventError::NotAllowed,
+                        &String::from("You cannot play against yourself"),
+                        &request,
+                    ));
+                } else if self.sockets.1.is_some() {
+                    addr.do_send(MSG::error(
+                        EventError::RoomFull,
+                        &String::from("Room Full"),
+                        &request,
+                    ));
+                } else {
+                    self.add_player(p2_socket.clone());
+                    let creator = self.sockets.0.clone();
+                    let creator_msg = ConnectMsg {
+                        opponent: p2_socket.name.clone(),
+                        color: self.creator_color,
+                    };
+                    let joiner_msg = ConnectMsg {
+                        opponent: creator.name,
+                        color: self.creator_color.opposite(),
+                    };
+                    self.send_to(&creator.id, ServerMsg::ConnectWith(creator_msg));
+                    self.send_to(&p2_socket.id, ServerMsg::ConnectWith(joiner_msg));
+                }
+            }
+            RoomCommands::OppReady => {
+                if let Some(pl2_socket) = self.sockets.1.clone() {
+                    let creator = self.sockets.0.id.clone();
+                    if sckt_id == pl2_socket.id {
+                        self.send_to(&creator, ServerMsg::OppReady(sckt_id));
                     } else {
-                        let msg = MSG::init(
-                            EventOrError::EventError(EventError::RoomFull),
-                            &String::from("Room is full"),
-                        );
-                        sckt.addr.unwrap().do_send(msg);
+                        self.send_to(&pl2_socket.id, ServerMsg::OppReady(creator));
                     }
                 } else {
-                    let msg = MSG::init(
-                        EventOrError::EventError(EventError::RoomFull),
-                        &String::from("No room found"),
+                    let msg = MSG::error(
+                        EventError::WaitingForOpponent,
+                        &String::from("Waiting for an opponent"),
+                        &request,
                     );
-                    // let e = MSG::init(
-                    sckt.addr.unwrap().do_send(msg);
+                    addr.do_send(msg);
                 }
             }
-            ServerCommands::Move(addr, socket_id, code, (i, j), (k, l)) => {
+            RoomCommands::Move((i, j), (k, l), promotion) => {
                 if i < 8 && j < 8 && k < 8 && l < 8 && (i != k || j != l) {
-                    let room = &mut self.find_room(code);
-                    if let Some(room) = room {
-                        if let Some(_) = room.get_addr_from_id(socket_id.clone()) {
-                            println!("{} {}", socket_id, room.turn);
-                            if socket_id == room.turn {
-                                if let Some(sib_sckt) = room.get_sibling_sckt(socket_id) {
-                                    room.turn = String::from(&sib_sckt.id);
-                                    let msg = MSG::init(
-                                        EventOrError::Event(Event::Move),
-                                        &serde_json::to_string(&HashMap::from([
-                                            ("i", i),
-                                            ("j", j),
-                                            ("k", k),
-                                            ("l", l),
-                                        ]))
-                                        .unwrap(),
-                                    );
-                                    sib_sckt.addr.unwrap().do_send(msg);
-                                } else {
+                    if self.is_player(&sckt_id) {
+                        println!("{} {}", sckt_id, self.turn);
+                        if let Some(accepted) = self.accepted_move(&sckt_id, &request) {
+                            addr.do_send(accepted);
+                        } else if self.sockets.1.is_none() {
+                            let msg = MSG::error(
+                                EventError::WaitingForOpponent,
+                                &String::from("Waiting for an opponent"),
+                                &request,
+                            );
+                            addr.do_send(msg);
+                        } else if self.color_of(&sckt_id) == self.board.side_to_move {
+                            match self.try_move((i, j), (k, l), promotion) {
+                                Err((error, reason)) => {
+                                    let msg = MSG::error(error, &reason, &request);
+                                    addr.do_send(msg);
                                 }
-                            } else {
-                                let msg = MSG::init(
-                                    EventOrError::EventError(EventError::RoomFull),
-                                    &String::from("Not your turn"),
-                                );
-                                addr.do_send(msg);
+                                // Held for a promotion piece; accepted once Promote arrives.
+                                Ok(()) if self.pending_promotion.is_some() => {}
+                                Ok(()) => addr.do_send(self.accept_move(&sckt_id, &request)),
                             }
+                            self.update_flag_timer(ctx);
                         } else {
-                            let msg = MSG::init(
-                                EventOrError::EventError(EventError::RoomFull),
-                                &String::from("You Are not in room"),
+                            let msg = MSG::error(
+                                EventError::NotYourTurn,
+                                &String::from("Not your turn"),
+                                &request,
                             );
                             addr.do_send(msg);
                         }
+                    } else if self.is_spectator(&sckt_id) {
+                        let msg = MSG::error(
+                            EventError::Spectating,
+                            &String::from("Spectators cannot move"),
+                            &request,
+                        );
+                        addr.do_send(msg);
                     } else {
-                        let msg = MSG::init(
-                            EventOrError::EventError(EventError::RoomFull),
-                            &String::from("No room found"),
+                        let msg = MSG::error(
+                            EventError::NotInRoom,
+                            &String::from("You Are not in room"),
+                            &request,
                         );
-                        addr.do_send(msg)
+                        addr.do_send(msg);
                     }
                 } else {
-                    let msg = MSG::init(
-                        EventOrError::EventError(EventError::RoomFull),
+                    let msg = MSG::error(
+                        EventError::IllegalMove,
                         &String::from("Invalid Move"),
+                        &request,
                     );
                     addr.do_send(msg)
                 }
             }
 
-            ServerCommands::Promote(room_code, sckt_id, (i, j), value) => {
-                let room = &mut self.find_room(room_code);
-                if let Some(room) = room {
-                    if room.turn == sckt_id {
-                        let sib_sckt = room.get_sibling_sckt(sckt_id).unwrap();
-                        room.turn = sib_sckt.id;
-                        sib_sckt.addr.unwrap().do_send(MSG::init(
-                            EventOrError::Event(Event::Promote),
-                            &serde_json::to_string(&HashMap::from([
-                                ("i", i.to_string()),
-                                ("j", j.to_string()),
-                                ("value", value),
-                            ]))
-                            .unwrap(),
+            RoomCommands::Promote((i, j), value) => {
+                if self.is_spectator(&sckt_id) {
+                    addr.do_send(MSG::error(
+                        EventError::Spectating,
+                        &String::from("Spectators cannot move"),
+                        &request,
+                    ));
+                } else if let Some(accepted) = self.accepted_move(&sckt_id, &request) {
+                    addr.do_send(accepted);
+                } else if self.is_player(&sckt_id)
+                    && self.color_of(&sckt_id) == self.board.side_to_move
+                {
+                    let promotion = match self.pending_promotion {
+                        Some((from, to)) if to == (i, j) => {
+                            self.complete_move(from, to, Some(value))
+                        }
+                        _ => Err((
+                            EventError::NoPendingRequest,
+                            String::from("No promotion pending on that square"),
+                        )),
+                    };
+                    match promotion {
+                        Err((error, reason)) => addr.do_send(MSG::error(error, &reason, &request)),
+                        Ok(()) => addr.do_send(self.accept_move(&sckt_id, &request)),
+                    }
+                    self.update_flag_timer(ctx);
+                } else {
+                    addr.do_send(MSG::error(
+                        EventError::NotYourTurn,
+                        &String::from("Not your turn"),
+                        &request,
+                    ))
+                }
+            }
+
+            RoomCommands::Spectate(sckt, password) => {
+                let viewer = Viewer {
+                    name: sckt.name.clone(),
+                    user_id: sckt.user_id,
+                    password,
+                };
+                if let Err(reason) = self.check_viewer(&sckt.id, &viewer) {
+                    addr.do_send(MSG::error(EventError::NotAllowed, &reason, &request));
+                } else {
+                    let state = self.state(&sckt.id);
+                    // Players already get every event; they only receive the state.
+                    if !self.is_player(&sckt.id) && !self.is_spectator(&sckt.id) {
+                        self.spectators.push(sckt);
+                    }
+                    addr.do_send(MSG::init(ServerMsg::Spectate(state)));
+                }
+            }
+
+            RoomCommands::Rejoin(new_socket, token) => {
+                if let Some(seat) = self.seat_by_token(&token) {
+                    // The seat keeps its id and token so the turn and the client's
+                    // stored session stay valid; only the connection is replaced.
+                    seat.addr = Some(addr.clone());
+                    seat.server = new_socket.server;
+                    addr.do_send(SetSession {
+                        id: String::from(&seat.id),
+                        token: String::from(&seat.token),
+                    });
+                    let id = String::from(&seat.id);
+                    if let Some(grace_timer) = self.away.remove(&id) {
+                        if let Some(handle) = grace_timer {
+                            ctx.cancel_future(handle);
+                        }
+                        if let Some(opp) = self.get_sibling_sckt(id.clone()) {
+                            self.send_to(&opp.id, ServerMsg::OpponentReconnected(id.clone()));
+                        }
+                    }
+                    addr.do_send(MSG::init(ServerMsg::Rejoin(self.state(&id))));
+                } else {
+                    addr.do_send(MSG::error(
+                        EventError::InvalidToken,
+                        &String::from("No seat in this room for that token"),
+                        &request,
+                    ));
+                }
+            }
+
+            RoomCommands::Disconnect => {
+                self.spectators.retain(|s| s.addr.as_ref() != Some(&addr));
+                // A seat that was taken over by Rejoin holds the newer address.
+                let seated = self.sockets.0.addr.as_ref() == Some(&addr)
+                    || self
+                        .sockets
+                        .1
+                        .as_ref()
+                        .is_some_and(|s| s.addr.as_ref() == Some(&addr));
+                if seated && (self.sockets.1.is_none() || self.outcome.is_some()) {
+                    self.away.insert(sckt_id.clone(), None);
+                } else if seated {
+                    self.start_grace_timer(&sckt_id, ctx);
+                    if let Some(opp) = self.get_sibling_sckt(sckt_id.clone()) {
+                        self.send_to(
+                            &opp.id,
+                            ServerMsg::OpponentDisconnected(Countdown {
+                                grace_secs: DISCONNECT_GRACE_SECS,
+                            }),
+                        );
+                    }
+                }
+            }
+
+            RoomCommands::Resign => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    let winner = self.color_of(&sckt_id).opposite();
+                    self.finish(GameResult::win_for(winner), GameOverReason::Resignation);
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::OfferDraw => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    self.make_offer(Offer::Draw, &sckt_id);
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::AcceptDraw => {
+                if self.check_active(&addr, &sckt_id, &request)
+                    && !self.accept_offer(Offer::Draw, &sckt_id)
+                {
+                    addr.do_send(MSG::error(
+                        EventError::NoPendingRequest,
+                        &String::from("No draw offer to accept"),
+                        &request,
+                    ));
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::DeclineDraw => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    match self.draw_offer.clone() {
+                        Some(offerer) if offerer != sckt_id => {
+                            self.draw_offer = None;
+                            self.send_to(&offerer, ServerMsg::DeclineDraw(sckt_id.clone()));
+                        }
+                        _ => addr.do_send(MSG::error(
+                            EventError::NoPendingRequest,
+                            &String::from("No draw offer to decline"),
+                            &request,
+                        )),
+                    }
+                }
+            }
+
+            RoomCommands::Abort => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    // Only allowed until both sides have made a move.
+                    if self.moves.len() < 2 {
+                        self.finish(GameResult::NoResult, GameOverReason::Aborted);
+                    } else {
+                        addr.do_send(MSG::error(
+                            EventError::NotAllowed,
+                            &String::from("Both players have moved, the game cannot be aborted"),
+                            &request,
+                        ));
+                    }
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::TakebackRequest => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    let error = if !self.casual {
+                        Some("Takebacks are only allowed in casual games")
+                    } else if self.takeback_plies(&sckt_id) > self.moves.len() {
+                        Some("Nothing to take back")
+                    } else {
+                        None
+                    };
+                    if let Some(error) = error {
+                        addr.do_send(MSG::error(
+                            EventError::NotAllowed,
+                            &String::from(error),
+                            &request,
                         ));
                     } else {
-                        room.get_addr_from_id(sckt_id).unwrap().do_send(MSG {
-                            event: EventOrError::EventError(EventError::RoomFull),
-                            message: String::from("Not your turn"),
-                        })
+                        self.takeback_request = Some(sckt_id.clone());
+                        let opp = self.get_sibling_sckt(sckt_id.clone()).unwrap();
+                        self.send_to(&opp.id, ServerMsg::TakebackRequest(sckt_id.clone()));
+                    }
+                }
+            }
+
+            RoomCommands::TakebackAccept => {
+                if self.check_active(&addr, &sckt_id, &request) {
+                    match self.takeback_request.clone() {
+                        Some(requester) if requester != sckt_id => {
+                            let plies = self.takeback_plies(&requester);
+                            self.take_back(plies);
+                            let msg = ServerMsg::TakebackAccept(TakebackMsg {
+                                plies,
+                                fen: self.board.to_fen(),
+                                turn: self.turn.clone(),
+                                clock: self.clock.as_ref().map(|clock| clock.times()),
+                            });
+                            self.send_to_players(&msg);
+                            self.send_to_spectators(&msg);
+                        }
+                        _ => addr.do_send(MSG::error(
+                            EventError::NoPendingRequest,
+                            &String::from("No takeback request to accept"),
+                            &request,
+                        )),
                     }
                 }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::Fen => {
+                if self.access.is_open() || self.is_player(&sckt_id) || self.is_spectator(&sckt_id)
+                {
+                    addr.do_send(MSG::init(ServerMsg::Fen(self.board.to_fen())));
+                } else {
+                    addr.do_send(MSG::error(
+                        EventError::NotAllowed,
+                        "Spectate this private room first",
+                        &request,
+                    ));
+                }
+            }
+
+            RoomCommands::Sync => {
+                if self.is_player(&sckt_id) || self.is_spectator(&sckt_id) {
+                    addr.do_send(MSG::init(ServerMsg::Sync(self.state(&sckt_id))));
+                } else {
+                    addr.do_send(MSG::error(
+                        EventError::NotInRoom,
+                        &String::from("You Are not in room"),
+                        &request,
+                    ));
+                }
             }
+
+            RoomCommands::RematchOffer => {
+                if self.check_finished(&addr, &sckt_id, &request) {
+                    self.make_offer(Offer::Rematch, &sckt_id);
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::RematchAccept => {
+                if self.check_finished(&addr, &sckt_id, &request)
+                    && !self.accept_offer(Offer::Rematch, &sckt_id)
+                {
+                    addr.do_send(MSG::error(
+                        EventError::NoPendingRequest,
+                        &String::from("No rematch offer to accept"),
+                        &request,
+                    ));
+                }
+                self.update_flag_timer(ctx);
+            }
+
+            RoomCommands::PromoteReq => match self.pending_promotion {
+                Some((from, to))
+                    if self.is_player(&sckt_id)
+                        && self.color_of(&sckt_id) == self.board.side_to_move =>
+                {
+                    addr.do_send(MSG::init(ServerMsg::PromoteReq(MovePayload::new(
+                        from, to, None,
+                    ))));
+                }
+                _ => addr.do_send(MSG::error(
+                    EventError::NoPendingRequest,
+                    &String::from("No promotion pending"),
+                    &request,
+                )),
+            },
         }
+        self.settle(ctx);
+    }
+}
+
+impl Handler<GetPgn> for Room {
+    type Result = Result<String, String>;
+    fn handle(&mut self, msg: GetPgn, _ctx: &mut Self::Context) -> Self::Result {
+        self.access
+            .check(&msg.0.name, msg.0.user_id, msg.0.password.as_deref())?;
+        return Ok(self.to_pgn());
+    }
+}
+
+impl Handler<GetRoomInfo> for Room {
+    type Result = MessageResult<GetRoomInfo>;
+    fn handle(&mut self, msg: GetRoomInfo, _ctx: &mut Self::Context) -> Self::Result {
+        let viewer = &msg.0;
+        let allowed = self
+            .access
+            .check(&viewer.name, viewer.user_id, viewer.password.as_deref());
+        return MessageResult(allowed.map(|_| self.info()));
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<Challenge>")]
+pub struct GetChallenges;
+
+impl Handler<GetChallenges> for Server {
+    type Result = Vec<Challenge>;
+    fn handle(&mut self, _msg: GetChallenges, _ctx: &mut Self::Context) -> Self::Result {
+        return self.challenges.clone();
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<Addr<Room>>")]
+pub struct FindRoom(pub RoomCode);
+
+impl Handler<FindRoom> for Server {
+    type Result = Option<Addr<Room>>;
+    fn handle(&mut self, msg: FindRoom, _ctx: &mut Self::Context) -> Self::Result {
+        return self
+            .rooms
+            .get(&msg.0)
+            .filter(|room| room.connected())
+            .cloned();
+    }
+}
+
+// Registers an account; answers None when the name is taken.
+#[derive(Message)]
+#[rtype(result = "Result<Option<i64>, String>")]
+pub struct CreateUser {
+    pub name: String,
+    pub password_hash: String,
+}
+
+impl Handler<CreateUser> for Server {
+    type Result = Result<Option<i64>, String>;
+    fn handle(&mut self, msg: CreateUser, _ctx: &mut Self::Context) -> Self::Result {
+        return self
+            .storage
+            .lock()
+            .unwrap()
+            .create_user(&msg.name, &msg.password_hash);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Option<UserRecord>, String>")]
+pub struct FindUser(pub String);
+
+impl Handler<FindUser> for Server {
+    type Result = Result<Option<UserRecord>, String>;
+    fn handle(&mut self, msg: FindUser, _ctx: &mut Self::Context) -> Self::Result {
+        return self.storage.lock().unwrap().user(&msg.0);
     }
 }
 
@@ -201,60 +895,1192 @@ impl Actor for Server {
     fn started(&mut self, ctx: &mut Self::Context) {
         println!("Server Started");
         self.addr = Some(ctx.address());
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.arbiters = (0..threads).map(|_| Arbiter::new().handle()).collect();
+        let writer = Writer::new(self.storage.clone());
+        self.writer = Some(Writer::start_in_arbiter(&Arbiter::new().handle(), |_ctx| {
+            writer
+        }));
+        self.load_games(ctx);
+    }
+}
+
+impl Actor for Room {
+    type Context = actix::Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Restored games may have a clock running already, and their players get the
+        // same grace period to come back as a dropped connection.
+        self.update_flag_timer(ctx);
+        if self.outcome.is_none() && self.sockets.1.is_some() {
+            let away: Vec<String> = self.away.keys().cloned().collect();
+            for id in away {
+                self.start_grace_timer(&id, ctx);
+            }
+        }
+        self.settle(ctx);
     }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 struct MSG {
-    event: EventOrError,
+    msg: ServerMsg,
+    // The client's request id this message answers.
+    id: Option<Value>,
+    // Position in the room's event stream for the receiving socket; None for replies.
+    seq: Option<u64>,
+}
+
+impl MSG {
+    fn init(msg: ServerMsg) -> Self {
+        return MSG {
+            msg,
+            id: None,
+            seq: None,
+        };
+    }
+    fn error(error: EventError, message: &str, request: &Value) -> Self {
+        return MSG {
+            msg: ServerMsg::Error(ErrorReply {
+                msg: ErrorMsg {
+                    code: error.code(),
+                    message: String::from(message),
+                    request: request.clone(),
+                },
+                event: error,
+            }),
+            id: request_id(request),
+            seq: None,
+        };
+    }
+}
+
+fn request_id(request: &Value) -> Option<Value> {
+    return request.get("id").filter(|id| !id.is_null()).cloned();
+}
+
+#[derive(Serialize, Clone)]
+struct ErrorMsg {
+    code: u16,
     message: String,
+    // The client message that failed, as received.
+    request: Value,
+}
+
+// Error replies use the error's name as the event.
+#[derive(Serialize, Clone)]
+struct ErrorReply {
+    event: EventError,
+    msg: ErrorMsg,
+}
+
+#[derive(Message, Clone)]
+#[rtype(result = "Socket")]
+struct GetSocket {}
+
+// Someone asking to look at a room without taking a seat.
+pub struct Viewer {
+    pub name: String,
+    pub user_id: Option<i64>,
+    pub password: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<String, String>")]
+pub struct GetPgn(pub Viewer);
+
+#[derive(Message)]
+#[rtype(result = "Result<RoomInfo, String>")]
+pub struct GetRoomInfo(pub Viewer);
+
+fn now_millis() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+}
+
+#[derive(Serialize, Clone)]
+pub struct MoveRecord {
+    pub san: String,
+    pub uci: String,
+    // Server time in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    // Both clocks right after the move.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockTimes>,
+}
+
+// One game, run as its own actor so games do not wait on each other.
+pub struct Room {
+    pub id: RoomCode,
+    pub turn: String,
+    pub sockets: (Socket, Option<Socket>),
+    pub board: Board,
+    pub pending_promotion: Option<(Square, Square)>,
+    pub positions: Vec<PositionKey>,
+    pub outcome: Option<(GameResult, GameOverReason)>,
+    pub start_fen: String,
+    pub moves: Vec<MoveRecord>,
+    pub created_at: u64,
+    pub clock: Option<Clock>,
+    // Players whose connection dropped, with the timer that ends an unfinished game.
+    pub away: HashMap<String, Option<SpawnHandle>>,
+    // Read-only observers; they get every move and the result but cannot play.
+    pub spectators: Vec<Socket>,
+    // Id of the player with an open draw offer.
+    pub draw_offer: Option<String>,
+    pub casual: bool,
+    // State before each move in `moves`, so takebacks can rewind.
+    pub history: Vec<Snapshot>,
+    // Id of the player waiting for a takeback to be accepted.
+    pub takeback_request: Option<String>,
+    // Moves already accepted, keyed by player id and client request id, so a resend
+    // of the same request is answered again instead of being played twice.
+    pub accepted: HashMap<(String, String), MoveAcceptedMsg>,
+    // Last sequence number sent to each player and spectator.
+    pub seqs: HashMap<String, u64>,
+    pub rated: bool,
+    pub public: bool,
+    // Who may take the second seat.
+    pub access: Access,
+    // The side the creator plays; whoever joins gets the other.
+    pub creator_color: Color,
+    pub rating_changes: Option<RatingChanges>,
+    pub saved: Saved,
+    pub server: Addr<Server>,
+    pub storage: SharedStorage,
+    pub writer: Addr<Writer>,
+    // The lobby entry the Server last heard about.
+    pub listed: Option<Challenge>,
+    // Id of the player with an open rematch offer.
+    pub rematch_offer: Option<String>,
+    // Earlier games between the two players in this room.
+    pub tally: Tally,
+}
+
+// Points per seat from the finished games before the current one.
+#[derive(Clone, Copy, Default)]
+pub struct Tally {
+    creator: f32,
+    opponent: f32,
+    games: u32,
+}
+
+// What a player can offer the other: both are settled once both players agree.
+#[derive(Clone, Copy)]
+enum Offer {
+    Draw,
+    Rematch,
+}
+
+// How much of a room has been handed to the writer.
+#[derive(Clone)]
+pub struct Saved {
+    key: GameKey,
+    created: bool,
+    black: bool,
+    moves: usize,
+    result: bool,
+}
+
+impl Saved {
+    fn new() -> Saved {
+        return Saved {
+            key: GameKey::unsaved(),
+            created: false,
+            black: false,
+            moves: 0,
+            result: false,
+        };
+    }
+}
+
+#[derive(Clone)]
+pub struct Snapshot {
+    board: Board,
+    turn: String,
+    clock: Option<(ClockTimes, Option<Color>)>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SeekOptions {
+    pub time_control: TimeControl,
+    // Inclusive bounds on the opponent's rating; either may be left open.
+    #[serde(default)]
+    pub rating_min: Option<u32>,
+    #[serde(default)]
+    pub rating_max: Option<u32>,
+    // Only rated seeks are paired with rated seeks.
+    #[serde(default)]
+    pub rated: bool,
+}
+
+pub struct Seeker {
+    socket: Socket,
+    rating: u32,
+    options: SeekOptions,
+}
+
+impl Seeker {
+    fn accepts(&self, other: &Seeker) -> bool {
+        return !self.socket.same_player(&other.socket)
+            && self.options.rated == other.options.rated
+            && self
+                .options
+                .rating_min
+                .is_none_or(|min| other.rating >= min)
+            && self
+                .options
+                .rating_max
+                .is_none_or(|max| other.rating <= max);
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct MoveAcceptedMsg {
+    // Index of the move in the game's move list.
+    ply: usize,
+    san: String,
+    uci: String,
+    // Both clocks right after the move, as the opponent sees them in Move.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<ClockTimes>,
+}
+
+#[derive(Serialize, Clone)]
+struct TakebackMsg {
+    plies: usize,
+    fen: String,
+    turn: String,
+    clock: Option<ClockTimes>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RoomOptions {
+    #[serde(default)]
+    pub fen: Option<String>,
+    #[serde(default)]
+    pub time_control: Option<TimeControl>,
+    // Casual rooms allow takebacks.
+    #[serde(default)]
+    pub casual: bool,
+    // Rated games change both players' ratings when they end.
+    #[serde(default)]
+    pub rated: bool,
+    // Public rooms are listed in the lobby until someone accepts.
+    #[serde(default)]
+    pub public: bool,
+    // Private rooms: joining needs the password and, when invitees are given, being one
+    // of them. Private rooms are never listed.
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub invitee: Option<String>,
+    #[serde(default)]
+    pub allowed_users: Vec<i64>,
+    // The creator's side.
+    #[serde(default)]
+    pub color: ColorChoice,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum ColorChoice {
+    #[default]
+    White,
+    Black,
+    Random,
+}
+
+impl ColorChoice {
+    fn resolve(self) -> Color {
+        match self {
+            ColorChoice::White => return Color::White,
+            ColorChoice::Black => return Color::Black,
+            ColorChoice::Random if rand::random::<bool>() => return Color::White,
+            ColorChoice::Random => return Color::Black,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+enum Variant {
+    Standard,
+    // Started from a custom FEN.
+    FromPosition,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RoomStatus {
+    // Nobody has taken the second seat yet.
+    Waiting,
+    Playing,
+    Finished,
+}
+
+// A room as seen through its invite link.
+#[derive(Serialize, Clone)]
+pub struct RoomInfo {
+    room_code: RoomCode,
+    status: RoomStatus,
+    white: Option<String>,
+    black: Option<String>,
+    time_control: Option<TimeControl>,
+    variant: Variant,
+    rated: bool,
+    // Joining needs a password or an invitation.
+    private: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<GameResult>,
+    spectators: usize,
+}
+
+// An open public room, as listed in the lobby.
+#[derive(Serialize, Clone, PartialEq)]
+pub struct Challenge {
+    room_code: RoomCode,
+    creator: String,
+    // The side the creator plays.
+    color: Color,
+    time_control: Option<TimeControl>,
+    variant: Variant,
+    rated: bool,
+}
+
+#[derive(Serialize, Clone)]
+struct MovePayload {
+    i: u8,
+    j: u8,
+    k: u8,
+    l: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    promotion: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<ClockTimes>,
 }
 
-impl MSG {
-    fn init(event: EventOrError, message: &String) -> Self {
-        return MSG {
-            event,
-            message: String::from(message),
+impl MovePayload {
+    fn new((i, j): Square, (k, l): Square, promotion: Option<String>) -> Self {
+        return MovePayload {
+            i,
+            j,
+            k,
+            l,
+            promotion,
+            clock: None,
         };
     }
 }
 
-#[derive(Message, Clone)]
-#[rtype(result = "Socket")]
-struct GetSocket {}
+#[derive(Serialize, Clone)]
+struct RoomState {
+    id: String,
+    room_code: RoomCode,
+    white: Option<String>,
+    black: Option<String>,
+    opponent: Option<String>,
+    // The recipient's side; None for spectators.
+    color: Option<Color>,
+    fen: String,
+    start_fen: String,
+    moves: Vec<MoveRecord>,
+    your_turn: bool,
+    pending_promotion: Option<MovePayload>,
+    clock: Option<ClockTimes>,
+    rated: bool,
+    result: Option<GameOverMsg>,
+    // Ids of the players with an open draw offer or takeback request.
+    draw_offer: Option<String>,
+    takeback_request: Option<String>,
+    // Last event sequence number sent to this socket; later events continue from it.
+    seq: u64,
+    rematch_offer: Option<String>,
+    score: MatchScore,
+}
 
-#[derive(Clone)]
-pub struct Room {
-    pub id: u16,
-    pub turn: String,
-    pub sockets: (Socket, Option<Socket>),
+// Points across the games played in a room, counting the current one once it is over.
+// Colors are those of the current game.
+#[derive(Serialize, Clone, Copy)]
+struct MatchScore {
+    white: f32,
+    black: f32,
+    games: u32,
+}
+
+#[derive(Serialize, Clone)]
+struct GameOverMsg {
+    result: GameResult,
+    reason: GameOverReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratings: Option<RatingChanges>,
+    score: MatchScore,
 }
 
 impl Room {
-    fn init(id: u16, p1_socket: Socket, p2_socket: Option<Socket>, turn: String) -> Room {
+    #[allow(clippy::too_many_arguments)]
+    fn init(
+        id: RoomCode,
+        p1_socket: Socket,
+        p2_socket: Option<Socket>,
+        turn: String,
+        board: Board,
+        clock: Option<Clock>,
+        casual: bool,
+        server: Addr<Server>,
+        storage: SharedStorage,
+        writer: Addr<Writer>,
+    ) -> Room {
         return Room {
             id,
             sockets: (p1_socket.clone(), p2_socket.clone()),
             turn,
+            positions: vec![board.position_key()],
+            board: board.clone(),
+            pending_promotion: None,
+            outcome: None,
+            start_fen: board.to_fen(),
+            moves: Vec::new(),
+            created_at: now_millis(),
+            clock,
+            away: HashMap::new(),
+            spectators: Vec::new(),
+            draw_offer: None,
+            casual,
+            history: Vec::new(),
+            takeback_request: None,
+            accepted: HashMap::new(),
+            seqs: HashMap::new(),
+            rated: false,
+            public: false,
+            access: Access::default(),
+            creator_color: Color::White,
+            rating_changes: None,
+            saved: Saved::new(),
+            server,
+            storage,
+            writer,
+            listed: None,
+            rematch_offer: None,
+            tally: Tally::default(),
+        };
+    }
+    // Whether `sckt_id` is playing a game in progress here; tells them why not otherwise.
+    fn check_active(&self, addr: &Addr<Socket>, sckt_id: &str, request: &Value) -> bool {
+        let (error, message) = if !self.is_player(sckt_id) {
+            (EventError::NotInRoom, "You Are not in room")
+        } else if self.sockets.1.is_none() {
+            (EventError::WaitingForOpponent, "Waiting for an opponent")
+        } else if self.outcome.is_some() {
+            (EventError::GameAlreadyOver, "Game is over")
+        } else {
+            return true;
+        };
+        addr.do_send(MSG::error(error, &String::from(message), request));
+        return false;
+    }
+    // Players always see their own room; anyone else has to pass the room's access rules.
+    fn check_viewer(&self, sckt_id: &str, viewer: &Viewer) -> Result<(), String> {
+        if self.is_player(sckt_id) {
+            return Ok(());
+        }
+        return self
+            .access
+            .check(&viewer.name, viewer.user_id, viewer.password.as_deref());
+    }
+    // Whether `sckt_id` is playing here and the game is over, so a rematch can start.
+    fn check_finished(&self, addr: &Addr<Socket>, sckt_id: &str, request: &Value) -> bool {
+        let (error, message) = if !self.is_player(sckt_id) {
+            (EventError::NotInRoom, "You Are not in room")
+        } else if self.sockets.1.is_none() {
+            (EventError::WaitingForOpponent, "Waiting for an opponent")
+        } else if self.outcome.is_none() {
+            (EventError::NotAllowed, "The game is still in progress")
+        } else {
+            return true;
+        };
+        addr.do_send(MSG::error(error, &String::from(message), request));
+        return false;
+    }
+    // Nobody can play here any more: a waiting room whose creator left, or a finished
+    // game that every player has left.
+    fn abandoned(&self) -> bool {
+        let creator_away = self.away.contains_key(&self.sockets.0.id);
+        let all_away = creator_away
+            && match self.sockets.1 {
+                Some(ref s) => self.away.contains_key(&s.id),
+                None => true,
+            };
+        return all_away && (self.outcome.is_some() || self.sockets.1.is_none());
+    }
+    // Run after anything that may have changed the room: stores what is new, keeps the
+    // lobby current and stops the room once it is abandoned.
+    fn settle(&mut self, ctx: &mut actix::Context<Self>) {
+        let mut writes = self.unsaved();
+        let challenge = self.challenge();
+        if challenge != self.listed {
+            self.listed = challenge.clone();
+            self.server
+                .do_send(RoomUpdate::Challenge(self.id.clone(), challenge));
+        }
+        if self.abandoned() {
+            // A game that never started is not worth keeping; finished ones stay stored.
+            if self.outcome.is_none() && self.saved.created {
+                writes.push(Write::DeleteRoom);
+            }
+            self.server.do_send(RoomUpdate::Closed(self.id.clone()));
+            ctx.stop();
+        }
+        if !writes.is_empty() {
+            self.writer.do_send(Persist(self.saved.key, writes));
+        }
+    }
+    // Grace period ran out: a game that never really started, or that both players have
+    // left, is aborted; otherwise the player who left forfeits.
+    fn abandon(&mut self, sckt_id: String, ctx: &mut actix::Context<Self>) {
+        if self.outcome.is_none() && self.away.contains_key(&sckt_id) {
+            self.away.insert(sckt_id.clone(), None);
+            let opponent_away = self
+                .get_sibling_sckt(sckt_id.clone())
+                .is_some_and(|opp| self.away.contains_key(&opp.id));
+            if self.moves.len() < 2 || opponent_away {
+                self.finish(GameResult::NoResult, GameOverReason::Aborted);
+            } else {
+                let winner = self.color_of(&sckt_id).opposite();
+                self.finish(GameResult::win_for(winner), GameOverReason::Abandoned);
+            }
+        }
+        self.settle(ctx);
+    }
+    fn start_grace_timer(&mut self, sckt_id: &str, ctx: &mut actix::Context<Self>) {
+        let id = String::from(sckt_id);
+        let handle = ctx.run_later(
+            Duration::from_secs(DISCONNECT_GRACE_SECS),
+            move |act, ctx| act.abandon(id, ctx),
+        );
+        self.away.insert(String::from(sckt_id), Some(handle));
+    }
+    // Re-arms the flag timer for whichever side's clock is now running.
+    fn update_flag_timer(&mut self, ctx: &mut actix::Context<Self>) {
+        if let Some(clock) = self.clock.as_mut() {
+            if let Some(handle) = clock.flag_timer.take() {
+                ctx.cancel_future(handle);
+            }
+            if self.outcome.is_some() {
+                return;
+            }
+            if let Some(color) = clock.running_side() {
+                let remaining = Duration::from_millis(clock.remaining(color));
+                clock.flag_timer = Some(ctx.run_later(remaining, move |act, ctx| {
+                    act.check_flag();
+                    act.settle(ctx);
+                }));
+            }
+        }
+    }
+    // Validates a move from the player whose turn it is. A pawn reaching the last rank
+    // without a promotion piece is held and the mover is asked for one via PromoteReq.
+    fn try_move(
+        &mut self,
+        from: Square,
+        to: Square,
+        promotion: Option<String>,
+    ) -> Result<(), (EventError, String)> {
+        if self.outcome.is_some() || self.check_flag() {
+            return Err((EventError::GameAlreadyOver, String::from("Game is over")));
+        }
+        if self.pending_promotion.is_some() {
+            return Err((
+                EventError::PromotionPending,
+                String::from("Promotion pending"),
+            ));
+        }
+        match self.board.check_move(from, to) {
+            Err(e) => return Err((EventError::IllegalMove, e.to_string())),
+            Ok(MoveKind::Promotion) if promotion.is_none() => {
+                self.pending_promotion = Some((from, to));
+                let mover = self.turn.clone();
+                self.send_to(
+                    &mover,
+                    ServerMsg::PromoteReq(MovePayload::new(from, to, None)),
+                );
+                return Ok(());
+            }
+            Ok(MoveKind::Promotion) => return self.complete_move(from, to, promotion),
+            Ok(_) if promotion.is_some() => {
+                return Err((
+                    EventError::IllegalMove,
+                    String::from("Only a pawn reaching the last rank can promote"),
+                ))
+            }
+            Ok(_) => return self.complete_move(from, to, None),
+        }
+    }
+    // Plays the move, hands the turn to the opponent and forwards the move to them.
+    fn complete_move(
+        &mut self,
+        from: Square,
+        to: Square,
+        promotion: Option<String>,
+    ) -> Result<(), (EventError, String)> {
+        let piece = match promotion.as_deref() {
+            Some(code) => match PieceKind::from_promotion_code(code) {
+                Some(piece) => Some(piece),
+                None => {
+                    return Err((
+                        EventError::IllegalMove,
+                        String::from("Invalid promotion piece"),
+                    ))
+                }
+            },
+            None => None,
+        };
+        let before = self.board.clone();
+        self.board
+            .make_move(from, to, piece)
+            .map_err(|e| (EventError::IllegalMove, e.to_string()))?;
+        self.history.push(Snapshot {
+            board: before.clone(),
+            turn: self.turn.clone(),
+            clock: self
+                .clock
+                .as_ref()
+                .map(|clock| (clock.times(), clock.running_side())),
+        });
+        self.takeback_request = None;
+        self.moves.push(MoveRecord {
+            san: before.san(from, to, piece),
+            uci: chess::uci((from, to), piece),
+            timestamp: now_millis(),
+            clock: None,
+        });
+        self.pending_promotion = None;
+        // A draw offer lapses once the player it was offered to makes a move instead.
+        if self.draw_offer.as_ref().is_some_and(|id| *id != self.turn) {
+            self.draw_offer = None;
+        }
+        if let Some(clock) = self.clock.as_mut() {
+            clock.press(before.side_to_move);
+            self.moves.last_mut().unwrap().clock = Some(clock.times());
+        }
+        self.record_position();
+        self.update_turn();
+        let mut payload = MovePayload::new(from, to, promotion);
+        payload.clock = self.clock.as_ref().map(|clock| clock.times());
+        let msg = ServerMsg::Move(payload);
+        let opponent = self.turn.clone();
+        self.send_to(&opponent, msg.clone());
+        self.send_to_spectators(&msg);
+        self.announce_game_over();
+        return Ok(());
+    }
+    // Confirms the move just played to its sender, remembering the request id if given.
+    fn accept_move(&mut self, sckt_id: &str, request: &Value) -> MSG {
+        let ply = self.moves.len() - 1;
+        let accepted = MoveAcceptedMsg {
+            ply,
+            san: self.moves[ply].san.clone(),
+            uci: self.moves[ply].uci.clone(),
+            clock: self.moves[ply].clock,
+        };
+        let id = request_id(request);
+        if let Some(ref id) = id {
+            self.accepted
+                .insert((String::from(sckt_id), id.to_string()), accepted.clone());
+        }
+        let mut msg = MSG::init(ServerMsg::MoveAccepted(accepted));
+        msg.id = id;
+        return msg;
+    }
+    // The earlier reply to a request this player already sent, if it was a move.
+    fn accepted_move(&self, sckt_id: &str, request: &Value) -> Option<MSG> {
+        let id = request_id(request)?;
+        let accepted = self
+            .accepted
+            .get(&(String::from(sckt_id), id.to_string()))?;
+        let mut msg = MSG::init(ServerMsg::MoveAccepted(accepted.clone()));
+        msg.id = Some(id);
+        return Some(msg);
+    }
+    fn to_pgn(&self) -> String {
+        let result = match self.outcome {
+            Some((result, _)) => serde_json::to_value(result).unwrap(),
+            None => Value::from("*"),
+        };
+        let name = |color| self.player(color).map_or("", |s| s.name.as_str());
+        return pgn::write_pgn(&PgnGame {
+            white: name(Color::White),
+            black: name(Color::Black),
+            result: result.as_str().unwrap(),
+            started_at: self.created_at,
+            start_fen: &self.start_fen,
+            moves: self.moves.iter().map(|m| m.san.as_str()).collect(),
+        });
+    }
+    // Call after every completed move so repetition and the game result stay current.
+    fn record_position(&mut self) {
+        let key = self.board.position_key();
+        let repeats = self.positions.iter().filter(|k| **k == key).count() + 1;
+        self.positions.push(key);
+        self.outcome = self.board.outcome();
+        if self.outcome.is_none() && repeats >= 3 {
+            self.outcome = Some((GameResult::Draw, GameOverReason::ThreefoldRepetition));
+        }
+        if self.outcome.is_some() {
+            if let Some(clock) = self.clock.as_mut() {
+                clock.stop();
+            }
+        }
+    }
+    // Ends the game if the side to move has run out of time. Losing on time is a draw
+    // when the opponent could never mate.
+    fn check_flag(&mut self) -> bool {
+        if self.outcome.is_some() {
+            return false;
+        }
+        let flagged = self.clock.as_ref().and_then(|clock| clock.flagged());
+        if let Some(color) = flagged {
+            let winner = color.opposite();
+            if self.board.has_mating_material(winner) {
+                self.finish(GameResult::win_for(winner), GameOverReason::Timeout);
+            } else {
+                self.finish(
+                    GameResult::Draw,
+                    GameOverReason::TimeoutVsInsufficientMaterial,
+                );
+            }
+            return true;
+        }
+        return false;
+    }
+    // Plies a takeback for `sckt_id` undoes: their last move, plus the reply if one was made.
+    fn takeback_plies(&self, sckt_id: &str) -> usize {
+        if self.turn == sckt_id {
+            return 2;
+        }
+        return 1;
+    }
+    fn take_back(&mut self, plies: usize) {
+        let target = self.history.len() - plies;
+        let snapshot = self.history[target].clone();
+        self.history.truncate(target);
+        self.moves.truncate(target);
+        self.positions.truncate(target + 1);
+        self.board = snapshot.board;
+        self.turn = snapshot.turn;
+        self.pending_promotion = None;
+        self.draw_offer = None;
+        self.takeback_request = None;
+        if let (Some(clock), Some((times, running))) = (self.clock.as_mut(), snapshot.clock) {
+            clock.restore(times, running);
+        }
+    }
+    fn record(&self) -> RoomRecord {
+        let player = |socket: &Socket| PlayerRecord {
+            id: socket.id.clone(),
+            token: socket.token.clone(),
+            name: socket.name.clone(),
+            user_id: socket.user_id,
+        };
+        return RoomRecord {
+            code: self.id.clone(),
+            white: self.player(Color::White).map(player),
+            black: self.player(Color::Black).map(player),
+            creator_color: self.creator_color,
+            start_fen: self.start_fen.clone(),
+            time_control: self.clock.as_ref().map(|clock| clock.time_control),
+            casual: self.casual,
+            rated: self.rated,
+            public: self.public,
+            created_at: self.created_at,
+            access: self.access.clone(),
+        };
+    }
+    // What changed since the room last handed its game to the writer.
+    fn unsaved(&mut self) -> Vec<Write> {
+        let mut writes = Vec::new();
+        if !self.saved.created {
+            writes.push(Write::CreateRoom(self.record()));
+            self.saved.created = true;
+            self.saved.black = self.sockets.1.is_some();
+        }
+        if self.sockets.1.is_some() && !self.saved.black {
+            writes.push(Write::UpdateRoom(self.record()));
+            self.saved.black = true;
+        }
+        if self.moves.len() < self.saved.moves {
+            writes.push(Write::TruncateMoves(self.moves.len()));
+            self.saved.moves = self.moves.len();
+        }
+        for ply in self.saved.moves..self.moves.len() {
+            let mv = &self.moves[ply];
+            writes.push(Write::AddMove(
+                ply,
+                MoveRow {
+                    uci: mv.uci.clone(),
+                    san: mv.san.clone(),
+                    timestamp: mv.timestamp,
+                    clock: mv.clock,
+                },
+            ));
+            self.saved.moves = ply + 1;
+        }
+        if let (Some((result, reason)), false) = (self.outcome, self.saved.result) {
+            let result = serde_json::to_value(result).unwrap();
+            let reason = serde_json::to_value(reason).unwrap();
+            writes.push(Write::SetResult(
+                String::from(result.as_str().unwrap()),
+                String::from(reason.as_str().unwrap()),
+            ));
+            self.saved.result = true;
+        }
+        return writes;
+    }
+    // Rebuilds a stored game by replaying its moves. Nobody is connected yet, so both
+    // seats start out away.
+    fn restore(
+        game: ActiveGame,
+        server: Addr<Server>,
+        storage: SharedStorage,
+        writer: Addr<Writer>,
+    ) -> Result<Room, String> {
+        let record = game.room;
+        let board = Board::from_fen(&record.start_fen)?;
+        let (creator, opponent) = match record.creator_color {
+            Color::White => (&record.white, &record.black),
+            Color::Black => (&record.black, &record.white),
+        };
+        let creator = creator
+            .as_ref()
+            .map(|player| Socket::offline(player, server.clone()))
+            .ok_or("Room has no creator")?;
+        let opponent = opponent
+            .as_ref()
+            .map(|player| Socket::offline(player, server.clone()));
+        let turn = creator.id.clone();
+        let mut room = Room::init(
+            record.code,
+            creator,
+            opponent,
+            turn,
+            board,
+            record.time_control.map(Clock::new),
+            record.casual,
+            server,
+            storage,
+            writer,
+        );
+        room.rated = record.rated;
+        room.public = record.public;
+        room.access = record.access;
+        room.creator_color = record.creator_color;
+        room.update_turn();
+        room.created_at = record.created_at;
+        for mv in game.moves.iter() {
+            let ((from, to), piece) =
+                chess::parse_uci(&mv.uci).ok_or(format!("Invalid move {}", mv.uci))?;
+            let promotion = piece.and_then(|p| p.promotion_code()).map(String::from);
+            room.complete_move(from, to, promotion)
+                .map_err(|(_, reason)| reason)?;
+            room.moves.last_mut().unwrap().timestamp = mv.timestamp;
+        }
+        let last_clock = game.moves.last().and_then(|mv| mv.clock);
+        if let (Some(clock), Some(times)) = (room.clock.as_mut(), last_clock) {
+            clock.restore(times, Some(room.board.side_to_move));
+            room.moves.last_mut().unwrap().clock = Some(times);
+        }
+        room.away.insert(room.sockets.0.id.clone(), None);
+        if let Some(ref opponent) = room.sockets.1 {
+            room.away.insert(opponent.id.clone(), None);
+        }
+        room.saved = Saved {
+            key: GameKey::Stored(game.id),
+            created: true,
+            black: room.sockets.1.is_some(),
+            moves: room.moves.len(),
+            result: false,
+        };
+        return Ok(room);
+    }
+    // Listed while the creator is still around and nobody has taken the other seat.
+    fn challenge(&self) -> Option<Challenge> {
+        let open = self.public
+            && self.access.is_open()
+            && self.sockets.1.is_none()
+            && self.outcome.is_none()
+            && !self.away.contains_key(&self.sockets.0.id);
+        if !open {
+            return None;
+        }
+        return Some(Challenge {
+            room_code: self.id.clone(),
+            creator: self.sockets.0.name.clone(),
+            color: self.creator_color,
+            time_control: self.clock.as_ref().map(|clock| clock.time_control),
+            variant: self.variant(),
+            rated: self.rated,
+        });
+    }
+    fn variant(&self) -> Variant {
+        if self.start_fen == Board::new().to_fen() {
+            return Variant::Standard;
+        }
+        return Variant::FromPosition;
+    }
+    // What an invite link shows before anyone joins.
+    fn info(&self) -> RoomInfo {
+        let status = if self.outcome.is_some() {
+            RoomStatus::Finished
+        } else if self.sockets.1.is_some() {
+            RoomStatus::Playing
+        } else {
+            RoomStatus::Waiting
+        };
+        return RoomInfo {
+            room_code: self.id.clone(),
+            status,
+            white: self.player(Color::White).map(|s| s.name.clone()),
+            black: self.player(Color::Black).map(|s| s.name.clone()),
+            time_control: self.clock.as_ref().map(|clock| clock.time_control),
+            variant: self.variant(),
+            rated: self.rated,
+            private: !self.access.is_open(),
+            result: self.outcome.map(|(result, _)| result),
+            spectators: self.spectators.len(),
+        };
+    }
+    fn category(&self) -> Option<Category> {
+        return self
+            .clock
+            .as_ref()
+            .map(|clock| Category::of(clock.time_control));
+    }
+    // Updates both players' ratings once a rated game has a result. The ratings are read
+    // when the game ends and stored under the same lock, so another game either player
+    // finished in the meantime is not overwritten. Aborted games leave ratings alone.
+    fn rate_game(&mut self) {
+        if !self.rated || self.rating_changes.is_some() {
+            return;
+        }
+        let players = (self.player(Color::White), self.player(Color::Black));
+        if let (Some(category), (Some(white), Some(black)), Some((result, _))) =
+            (self.category(), players, self.outcome)
+        {
+            // One account in both seats must never move its own rating.
+            if white.user_id.is_some() && white.user_id == black.user_id {
+                return;
+            }
+            let score = match result {
+                GameResult::WhiteWins => 1.0,
+                GameResult::BlackWins => 0.0,
+                GameResult::Draw => 0.5,
+                GameResult::NoResult => return,
+            };
+            let mut storage = self.storage.lock().unwrap();
+            let old_white = rating_of(&*storage, &white.name, category);
+            let old_black = rating_of(&*storage, &black.name, category);
+            let new_white = old_white.update(&[(old_black, score)]);
+            let new_black = old_black.update(&[(old_white, 1.0 - score)]);
+            for (name, rating) in [(&white.name, new_white), (&black.name, new_black)] {
+                if let Err(err) = storage.save_rating(name, category, rating) {
+                    println!("storage: {}", err);
+                }
+            }
+            drop(storage);
+            self.rating_changes = Some(RatingChanges {
+                white: RatingChange::new(old_white, new_white),
+                black: RatingChange::new(old_black, new_black),
+            });
+        }
+    }
+    // Ends the game for a reason other than the position on the board.
+    fn finish(&mut self, result: GameResult, reason: GameOverReason) {
+        self.outcome = Some((result, reason));
+        self.draw_offer = None;
+        if let Some(clock) = self.clock.as_mut() {
+            clock.stop();
+        }
+        self.announce_game_over();
+    }
+    fn offer_slot(&mut self, offer: Offer) -> &mut Option<String> {
+        match offer {
+            Offer::Draw => return &mut self.draw_offer,
+            Offer::Rematch => return &mut self.rematch_offer,
+        }
+    }
+    fn agree(&mut self, offer: Offer) {
+        match offer {
+            Offer::Draw => self.finish(GameResult::Draw, GameOverReason::Agreement),
+            Offer::Rematch => self.rematch(),
+        }
+    }
+    // Offering back to someone who already offered settles it; otherwise the opponent is
+    // asked.
+    fn make_offer(&mut self, offer: Offer, sckt_id: &str) {
+        let opp = self.get_sibling_sckt(String::from(sckt_id)).unwrap();
+        if self.offer_slot(offer).as_ref() == Some(&opp.id) {
+            self.agree(offer);
+            return;
+        }
+        *self.offer_slot(offer) = Some(String::from(sckt_id));
+        let msg = match offer {
+            Offer::Draw => ServerMsg::OfferDraw(String::from(sckt_id)),
+            Offer::Rematch => ServerMsg::RematchOffer(String::from(sckt_id)),
+        };
+        self.send_to(&opp.id, msg);
+    }
+    // Settles the opponent's open offer; false when there is none.
+    fn accept_offer(&mut self, offer: Offer, sckt_id: &str) -> bool {
+        match self.offer_slot(offer).clone() {
+            Some(offerer) if offerer != sckt_id => {
+                self.agree(offer);
+                return true;
+            }
+            _ => return false,
+        }
+    }
+    // Starts the next game between the same players: colors swap, everything else about
+    // the room stays, and the game is stored as a new one.
+    fn rematch(&mut self) {
+        let score = self.match_score();
+        let (creator, opponent) = match self.creator_color {
+            Color::White => (score.white, score.black),
+            Color::Black => (score.black, score.white),
+        };
+        let mut next = Room::init(
+            self.id.clone(),
+            self.sockets.0.clone(),
+            self.sockets.1.clone(),
+            String::new(),
+            Board::from_fen(&self.start_fen).unwrap(),
+            self.clock
+                .as_ref()
+                .map(|clock| Clock::new(clock.time_control)),
+            self.casual,
+            self.server.clone(),
+            self.storage.clone(),
+            self.writer.clone(),
+        );
+        next.rated = self.rated;
+        next.public = self.public;
+        next.access = self.access.clone();
+        next.creator_color = self.creator_color.opposite();
+        next.away = std::mem::take(&mut self.away);
+        next.spectators = std::mem::take(&mut self.spectators);
+        next.seqs = std::mem::take(&mut self.seqs);
+        next.listed = self.listed.take();
+        next.tally = Tally {
+            creator,
+            opponent,
+            games: score.games,
+        };
+        *self = next;
+        self.update_turn();
+        let mut ids = vec![self.sockets.0.id.clone()];
+        ids.extend(self.sockets.1.iter().map(|s| s.id.clone()));
+        ids.extend(self.spectators.iter().map(|s| s.id.clone()));
+        for id in ids {
+            let state = self.state(&id);
+            self.send_to(&id, ServerMsg::Rematch(state));
+        }
+    }
+    // Earlier games plus the current one once it has a result; aborted games do not count.
+    fn match_score(&self) -> MatchScore {
+        let (white, black, played) = match self.outcome {
+            Some((GameResult::WhiteWins, _)) => (1.0, 0.0, 1),
+            Some((GameResult::BlackWins, _)) => (0.0, 1.0, 1),
+            Some((GameResult::Draw, _)) => (0.5, 0.5, 1),
+            _ => (0.0, 0.0, 0),
+        };
+        let (earlier_white, earlier_black) = match self.creator_color {
+            Color::White => (self.tally.creator, self.tally.opponent),
+            Color::Black => (self.tally.opponent, self.tally.creator),
+        };
+        return MatchScore {
+            white: earlier_white + white,
+            black: earlier_black + black,
+            games: self.tally.games + played,
+        };
+    }
+    fn announce_game_over(&mut self) {
+        if let Some((result, reason)) = self.outcome {
+            self.rate_game();
+            let msg = ServerMsg::GameOver(GameOverMsg {
+                result,
+                reason,
+                ratings: self.rating_changes,
+                score: self.match_score(),
+            });
+            self.send_to_players(&msg);
+            self.send_to_spectators(&msg);
+        }
+    }
+    fn send_to_spectators(&mut self, msg: &ServerMsg) {
+        let ids: Vec<String> = self.spectators.iter().map(|s| s.id.clone()).collect();
+        for id in ids {
+            self.send_to(&id, msg.clone());
+        }
+    }
+    // Sends a room event to one player or spectator, numbered with the next value of
+    // their sequence so a client can spot a missed message and ask for a Sync.
+    fn send_to(&mut self, sckt_id: &str, msg: ServerMsg) {
+        let addr = if self.is_player(sckt_id) {
+            self.get_addr_from_id(String::from(sckt_id))
+        } else {
+            self.spectators
+                .iter()
+                .find(|s| s.id == sckt_id)
+                .and_then(|s| s.addr.clone())
         };
+        let seq = self.seqs.entry(String::from(sckt_id)).or_insert(0);
+        *seq += 1;
+        if let Some(addr) = addr {
+            addr.do_send(MSG {
+                msg,
+                id: None,
+                seq: Some(*seq),
+            });
+        }
+    }
+    fn is_player(&self, sckt_id: &str) -> bool {
+        return self.sockets.0.id == sckt_id
+            || self.sockets.1.as_ref().is_some_and(|s| s.id == sckt_id);
+    }
+    fn is_spectator(&self, sckt_id: &str) -> bool {
+        return self.spectators.iter().any(|s| s.id == sckt_id);
+    }
+    fn send_to_players(&mut self, msg: &ServerMsg) {
+        let creator = self.sockets.0.id.clone();
+        self.send_to(&creator, msg.clone());
+        if let Some(pl2) = self.sockets.1.clone() {
+            self.send_to(&pl2.id, msg.clone());
+        }
     }
     fn add_player(&mut self, pl_socket: Socket) {
-        self.sockets.1 = Some(pl_socket.clone());
+        self.sockets.1 = Some(pl_socket);
+        self.update_turn();
     }
-    fn get_pl1_addr(&mut self) -> Addr<Socket> {
-        return self.sockets.0.clone().addr.unwrap();
+    // The seat playing `color`, None while it is still open.
+    fn player(&self, color: Color) -> Option<&Socket> {
+        if color == self.creator_color {
+            return Some(&self.sockets.0);
+        }
+        return self.sockets.1.as_ref();
     }
-    fn get_pl2_addr(&mut self) -> Addr<Socket> {
-        return self.sockets.1.clone().unwrap().addr.unwrap();
+    // Points `turn` at whoever plays the side to move, or at nobody while that seat is open.
+    fn update_turn(&mut self) {
+        self.turn = self
+            .player(self.board.side_to_move)
+            .map(|s| s.id.clone())
+            .unwrap_or_default();
     }
     fn get_addr_from_id(&mut self, sckt_id: String) -> Option<Addr<Socket>> {
         if sckt_id == self.sockets.0.id {
-            return Some(self.sockets.clone().0.addr.unwrap());
+            return self.sockets.0.addr.clone();
         } else {
             if let Some(pl2) = self.sockets.1.clone() {
                 if sckt_id == pl2.id {
-                    return Some(pl2.addr.unwrap());
+                    return pl2.addr;
                 }
             }
         }
@@ -272,6 +2098,55 @@ impl Room {
         }
         return None;
     }
+    fn color_of(&self, sckt_id: &str) -> Color {
+        if self.sockets.0.id == sckt_id {
+            return self.creator_color;
+        }
+        return self.creator_color.opposite();
+    }
+    fn seat_by_token(&mut self, token: &str) -> Option<&mut Socket> {
+        if self.sockets.0.token == token {
+            return Some(&mut self.sockets.0);
+        }
+        return self.sockets.1.as_mut().filter(|s| s.token == token);
+    }
+    // Everything a client needs to redraw the game from scratch.
+    fn state(&self, for_id: &str) -> RoomState {
+        let opponent = if self.is_player(for_id) {
+            let opponent = self.player(self.color_of(for_id).opposite());
+            opponent.map(|s| s.name.clone())
+        } else {
+            None
+        };
+        return RoomState {
+            id: String::from(for_id),
+            room_code: self.id.clone(),
+            white: self.player(Color::White).map(|s| s.name.clone()),
+            black: self.player(Color::Black).map(|s| s.name.clone()),
+            opponent,
+            color: Some(self.color_of(for_id)).filter(|_| self.is_player(for_id)),
+            fen: self.board.to_fen(),
+            start_fen: self.start_fen.clone(),
+            moves: self.moves.clone(),
+            your_turn: self.turn == for_id,
+            pending_promotion: self
+                .pending_promotion
+                .map(|(from, to)| MovePayload::new(from, to, None)),
+            clock: self.clock.as_ref().map(|clock| clock.times()),
+            rated: self.rated,
+            result: self.outcome.map(|(result, reason)| GameOverMsg {
+                result,
+                reason,
+                ratings: self.rating_changes,
+                score: self.match_score(),
+            }),
+            draw_offer: self.draw_offer.clone(),
+            takeback_request: self.takeback_request.clone(),
+            seq: self.seqs.get(for_id).copied().unwrap_or(0),
+            rematch_offer: self.rematch_offer.clone(),
+            score: self.match_score(),
+        };
+    }
     fn display(&self) {
         let p1id = self.sockets.0.clone().id;
         let pl2id = if let Some(ref s) = self.sockets.1 {
@@ -286,35 +2161,156 @@ impl Room {
 #[derive(MessageResponse, Clone)]
 pub struct Socket {
     pub id: String,
+    // Secret handed out in the Start event; lets a new connection take over this seat.
+    pub token: String,
     pub name: String,
     pub addr: Option<Addr<Socket>>, // pub server: Addr<Server>,
     pub server: Addr<Server>,
+    // Rooms this connection created, joined or watches, so commands skip the Server.
+    pub rooms: HashMap<RoomCode, Addr<Room>>,
+    pub window_start: Instant,
+    pub window_count: u32,
+    pub protocol: Protocol,
+    // The account this connection logged in as; None for guests.
+    pub user_id: Option<i64>,
 }
 
+// Messages a socket may send per second before being told to slow down.
+const RATE_LIMIT_PER_SEC: u32 = 20;
+
 impl Socket {
+    // A seat in a restored game whose player has not reconnected yet.
+    fn offline(player: &PlayerRecord, server: Addr<Server>) -> Socket {
+        return Socket {
+            id: player.id.clone(),
+            token: player.token.clone(),
+            name: player.name.clone(),
+            addr: None,
+            server,
+            rooms: HashMap::new(),
+            window_start: Instant::now(),
+            window_count: 0,
+            protocol: Protocol::V1,
+            user_id: player.user_id,
+        };
+    }
+    // The same connection or account, or for guests the same name, e.g. a second tab.
+    fn same_player(&self, other: &Socket) -> bool {
+        if self.id == other.id {
+            return true;
+        }
+        match (self.user_id, other.user_id) {
+            (Some(id), Some(other_id)) => return id == other_id,
+            (None, None) => return self.name.eq_ignore_ascii_case(&other.name),
+            _ => return false,
+        }
+    }
+    // Logged in players always go by their account name.
     fn set_name(&mut self, name: String) {
-        self.name = String::from(&name);
+        if self.user_id.is_none() {
+            self.name = String::from(&name);
+        }
+    }
+    fn send_command(&self, request: &Value, command: ServerCommands) {
+        self.server.do_send(ClientRequest {
+            command,
+            request: request.clone(),
+        });
+    }
+    // Rooms this socket knows get the command directly. Otherwise the Server is asked
+    // where the room is; waiting for the answer keeps the client's messages in order.
+    fn send_room_command(
+        &mut self,
+        request: &Value,
+        code: RoomCode,
+        command: RoomCommands,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let msg = RoomRequest {
+            addr: self.addr.clone().unwrap(),
+            sckt_id: self.id.clone(),
+            command,
+            request: request.clone(),
+        };
+        match self.rooms.get(&code) {
+            Some(room) if room.connected() => return room.do_send(msg),
+            // The room closed; its code may belong to a new room by now.
+            Some(_) => {
+                self.rooms.remove(&code);
+            }
+            None => {}
+        }
+        let lookup = self
+            .server
+            .send(FindRoom(code.clone()))
+            .into_actor(self)
+            .map(move |room, socket, ctx| match room {
+                Ok(Some(room)) => {
+                    if msg.command.joins() {
+                        socket.rooms.insert(code, room.clone());
+                    }
+                    room.do_send(msg);
+                }
+                _ => {
+                    let reply = MSG::error(EventError::RoomNotFound, "No room found", &msg.request);
+                    ctx.text(socket.encode(&reply));
+                }
+            });
+        ctx.wait(lookup);
+    }
+    fn rate_limited(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.window_count = 0;
+        }
+        self.window_count += 1;
+        return self.window_count > RATE_LIMIT_PER_SEC;
+    }
+}
+
+// Sent to a socket that rejoined a room so it takes over the old connection's identity.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SetSession {
+    id: String,
+    token: String,
+}
+
+// Sent by the Server to the sockets it seats in a new room.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Seated(RoomCode, Addr<Room>);
+
+impl Handler<Seated> for Socket {
+    type Result = ();
+    fn handle(&mut self, msg: Seated, _ctx: &mut Self::Context) -> Self::Result {
+        self.rooms.insert(msg.0, msg.1);
+    }
+}
+
+impl Handler<SetSession> for Socket {
+    type Result = ();
+    fn handle(&mut self, msg: SetSession, _ctx: &mut Self::Context) -> Self::Result {
+        self.id = msg.id;
+        self.token = msg.token;
     }
 }
 
+#[derive(Serialize, Clone)]
+struct Session {
+    id: String,
+    token: String,
+    // The account name for logged in connections.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
 impl Handler<MSG> for Socket {
     type Result = ();
     fn handle(&mut self, msg: MSG, ctx: &mut Self::Context) -> Self::Result {
-        let event: String = match msg.event {
-            EventOrError::Event(e) => e.to_string(),
-            EventOrError::EventError(e) => e.to_string(),
-        };
-        let msg = msg.message;
-        let x: Result<Value, serde_json::Error> = serde_json::from_str(&msg.clone());
-        if let Ok(y) = x {
-            let res = serde_json::to_string(&WsMsg { event, msg: y }).unwrap();
-            println!("{}", res);
-            ctx.text(res);
-        } else {
-            let res = serde_json::to_string(&WsMsg { event, msg }).unwrap();
-            println!("{}", res);
-            ctx.text(res);
-        }
+        let res = self.encode(&msg);
+        println!("{}", res);
+        ctx.text(res);
     }
 }
 
@@ -329,83 +2325,470 @@ impl Actor for Socket {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         self.id = Uuid::new_v4().to_string();
+        self.token = Uuid::new_v4().to_string();
         self.addr = Some(ctx.address());
-        let text = create_ws_msg(EventOrError::Event(Event::Start), &self.id).unwrap();
+        let text = self.encode(&MSG::init(ServerMsg::Start(Session {
+            id: self.id.clone(),
+            token: self.token.clone(),
+            user: self.user_id.map(|_| self.name.clone()),
+        })));
         ctx.text(text);
         println!("Start");
     }
 
-    // fn
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        let addr = self.addr.clone().unwrap();
+        for room in self.rooms.values() {
+            room.do_send(RoomRequest {
+                addr: addr.clone(),
+                sckt_id: self.id.clone(),
+                command: RoomCommands::Disconnect,
+                request: Value::Null,
+            });
+        }
+        self.send_command(&Value::Null, ServerCommands::Disconnect(addr));
+    }
+}
+
+// Wire protocols a client can ask for in the Sec-WebSocket-Protocol header. Clients that
+// send none speak v1, where `msg` is a string that may itself hold JSON; v2 clients send
+// a flat object with the fields next to `event`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+    V2,
 }
-#[derive(Serialize, Deserialize, Clone)]
-enum Event {
-    Move,
-    GameOver,
-    Start,
-    GetCode,
-    ConnectWith,
-    OppReady,
-    Promote,
-    PromoteReq,
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::V1 => return "chess.v1",
+            Protocol::V2 => return "chess.v2",
+        }
+    }
+    fn version(&self) -> u8 {
+        match self {
+            Protocol::V1 => return 1,
+            Protocol::V2 => return 2,
+        }
+    }
+    // The first protocol in the client's list that the server speaks.
+    pub fn negotiate(requested: &str) -> Option<Protocol> {
+        return requested
+            .split(',')
+            .map(|name| name.trim())
+            .find_map(|name| match name {
+                "chess.v1" => Some(Protocol::V1),
+                "chess.v2" => Some(Protocol::V2),
+                _ => None,
+            });
+    }
+}
+
+// Everything a client can send, tagged by `event`. Any message may also carry an `id`,
+// which is echoed in the reply.
+#[derive(Deserialize)]
+#[serde(tag = "event")]
+enum ClientMsg {
+    GetCode {
+        name: String,
+        #[serde(flatten)]
+        options: RoomOptions,
+    },
+    ConnectWith {
+        room_code: RoomCode,
+        name: String,
+        // Needed for rooms created with a password.
+        #[serde(default)]
+        password: Option<String>,
+    },
+    OppReady {
+        room_code: RoomCode,
+    },
+    Move {
+        room_code: RoomCode,
+        i: u8,
+        j: u8,
+        k: u8,
+        l: u8,
+        #[serde(default)]
+        promotion: Option<String>,
+    },
+    Promote {
+        room_code: RoomCode,
+        i: u8,
+        j: u8,
+        promote_to: String,
+    },
+    // Asks the server to repeat a pending promotion prompt.
+    PromoteReq {
+        room_code: RoomCode,
+    },
+    Fen {
+        room_code: RoomCode,
+    },
+    Rejoin {
+        room_code: RoomCode,
+        token: String,
+    },
+    Spectate {
+        room_code: RoomCode,
+        // Private rooms can only be watched by those who could join them.
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Resign {
+        room_code: RoomCode,
+    },
+    OfferDraw {
+        room_code: RoomCode,
+    },
+    AcceptDraw {
+        room_code: RoomCode,
+    },
+    DeclineDraw {
+        room_code: RoomCode,
+    },
+    Abort {
+        room_code: RoomCode,
+    },
+    TakebackRequest {
+        room_code: RoomCode,
+    },
+    TakebackAccept {
+        room_code: RoomCode,
+    },
+    // Asks for a full snapshot of the room, e.g. after a gap in `seq`.
+    Sync {
+        room_code: RoomCode,
+    },
+    // After a game ends: play again in the same room with colors swapped.
+    RematchOffer {
+        room_code: RoomCode,
+    },
+    RematchAccept {
+        room_code: RoomCode,
+    },
+    // Joins the matchmaking pool for a time control.
+    Seek {
+        name: String,
+        #[serde(flatten)]
+        options: SeekOptions,
+    },
+    CancelSeek,
+    // Follows the lobby: a `Lobby` snapshot, then `ChallengeCreated`/`ChallengeRemoved`.
+    SubscribeLobby,
+    UnsubscribeLobby,
+}
+
+// Everything the server sends, as {"event": <variant>, "msg": <payload>}.
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", content = "msg")]
+enum ServerMsg {
+    Start(Session),
+    GetCode(IdAndCode),
+    ConnectWith(ConnectMsg),
+    OppReady(String),
+    Move(MovePayload),
+    MoveAccepted(MoveAcceptedMsg),
+    PromoteReq(MovePayload),
+    GameOver(GameOverMsg),
+    Fen(String),
+    Spectate(RoomState),
+    Rejoin(RoomState),
+    Sync(RoomState),
+    Seeking(SeekOptions),
+    SeekCancelled,
+    // A seek found an opponent and the game has started.
+    Matched(RoomState),
+    OpponentDisconnected(Countdown),
+    OpponentReconnected(String),
+    OfferDraw(String),
+    DeclineDraw(String),
+    TakebackRequest(String),
+    TakebackAccept(TakebackMsg),
+    RematchOffer(String),
+    // The rematch has started; a fresh state for each player and spectator.
+    Rematch(RoomState),
+    Lobby(Vec<Challenge>),
+    ChallengeCreated(Challenge),
+    // The code of a challenge that was accepted or withdrawn.
+    ChallengeRemoved(RoomCode),
+    #[serde(untagged)]
+    Error(ErrorReply),
+}
+
+#[derive(Serialize)]
+struct ServerEnvelope<'a> {
+    // Left out for v1 clients, which predate it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<u8>,
+    #[serde(flatten)]
+    msg: &'a ServerMsg,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+}
+
+// Sent to both players once the second one joins.
+#[derive(Serialize, Clone)]
+struct ConnectMsg {
+    opponent: String,
+    // The recipient's side.
+    color: Color,
+}
+
+#[derive(Serialize, Clone)]
+struct IdAndCode {
+    id: String,
+    code: RoomCode,
+}
+
+#[derive(Serialize, Clone)]
+struct Countdown {
+    grace_secs: u64,
 }
+
 #[derive(Clone, Serialize, Deserialize)]
+// Sent as the event name of an error reply. Codes are stable: 1xxx for malformed
+// requests, 2xxx for room membership and 3xxx for game rules.
 enum EventError {
     ParseError,
+    UnknownEvent,
     InvalidCode,
+    InvalidFen,
+    RateLimited,
+    RoomNotFound,
     RoomFull,
+    NotInRoom,
+    WaitingForOpponent,
+    Spectating,
+    InvalidToken,
+    NotYourTurn,
+    IllegalMove,
+    PromotionPending,
+    GameAlreadyOver,
+    NoPendingRequest,
+    NotAllowed,
 }
 
 impl EventError {
-    fn to_string(&self) -> String {
+    fn code(&self) -> u16 {
         match self {
-            EventError::ParseError => return String::from("Parsing Error"),
-            EventError::InvalidCode => return String::from("Invalid Code"),
-            EventError::RoomFull => return String::from("Room Full"),
+            EventError::ParseError => return 1000,
+            EventError::UnknownEvent => return 1001,
+            EventError::InvalidCode => return 1002,
+            EventError::InvalidFen => return 1003,
+            EventError::RateLimited => return 1004,
+            EventError::RoomNotFound => return 2000,
+            EventError::RoomFull => return 2001,
+            EventError::NotInRoom => return 2002,
+            EventError::WaitingForOpponent => return 2003,
+            EventError::Spectating => return 2004,
+            EventError::InvalidToken => return 2005,
+            EventError::NotYourTurn => return 3000,
+            EventError::IllegalMove => return 3001,
+            EventError::PromotionPending => return 3002,
+            EventError::GameAlreadyOver => return 3003,
+            EventError::NoPendingRequest => return 3004,
+            EventError::NotAllowed => return 3005,
         }
     }
 }
 
-impl Event {
-    fn to_string(&self) -> String {
-        match self {
-            Event::Move => return String::from("Move"),
-            Event::GameOver => return String::from("GameOver"),
-            Event::Start => return String::from("Start"),
-            Event::ConnectWith => return String::from("ConnectWith"),
-            Event::GetCode => return String::from("GetCode"),
-            Event::OppReady => return String::from("OppReady"),
-            Event::Promote => return String::from("Promote"),
-            Event::PromoteReq => return String::from("PromoteReq"),
+// v1 envelope: `msg` is a string holding JSON for moves and joins, and a bare room code
+// or name for everything else.
+#[derive(Deserialize)]
+struct WsMsg {
+    event: String,
+    msg: String,
+}
+
+fn v1_room_code(code: &Value) -> Result<RoomCode, (EventError, String)> {
+    match code {
+        Value::String(code) => {
+            return RoomCode::try_from(code.clone()).map_err(|err| (EventError::InvalidCode, err))
         }
+        _ => return Err((EventError::InvalidCode, String::from("Invalid room code"))),
     }
-    fn from_string(string: &str) -> Result<Event, EventError> {
-        match string {
-            "Move" => return Ok(Event::Move),
-            "GameOver" => return Ok(Event::GameOver),
-            "Start" => return Ok(Event::Start),
-            "ConnectWith" => return Ok(Event::ConnectWith),
-            "GetCode" => return Ok(Event::GetCode),
-            "OppReady" => return Ok(Event::OppReady),
-            "Promote" => return Ok(Event::Promote),
-            "PromoteReq" => return Ok(Event::PromoteReq),
-            _ => return Err(EventError::ParseError),
+}
+
+// Rewrites a v1 message into the v2 shape so both decode into the same ClientMsg.
+fn decode_v1(text: &str) -> Result<ClientMsg, (EventError, String)> {
+    let ws_msg = serde_json::from_str::<WsMsg>(text)
+        .map_err(|err| (EventError::ParseError, err.to_string()))?;
+    let WsMsg { event, msg } = ws_msg;
+    let mut fields = match event.as_str() {
+        "Move" | "ConnectWith" | "Promote" | "Rejoin" | "Seek" => {
+            match serde_json::from_str::<Value>(&msg) {
+                Ok(Value::Object(fields)) => fields,
+                _ => return Err((EventError::ParseError, String::from("Invalid json"))),
+            }
         }
+        // Either a bare name or {"name", ...RoomOptions}.
+        "GetCode" => match serde_json::from_str::<Value>(&msg) {
+            Ok(Value::Object(fields)) => fields,
+            _ => {
+                let mut fields = serde_json::Map::new();
+                fields.insert(String::from("name"), Value::String(msg));
+                fields
+            }
+        },
+        "OppReady" | "PromoteReq" | "Fen" | "Spectate" | "Resign" | "OfferDraw" | "AcceptDraw"
+        | "DeclineDraw" | "Abort" | "TakebackRequest" | "TakebackAccept" | "Sync"
+        | "RematchOffer" | "RematchAccept" => {
+            let mut fields = serde_json::Map::new();
+            fields.insert(String::from("room_code"), Value::String(msg));
+            fields
+        }
+        "CancelSeek" | "SubscribeLobby" | "UnsubscribeLobby" => serde_json::Map::new(),
+        _ => {
+            return Err((
+                EventError::UnknownEvent,
+                String::from("Error While Parsing Event"),
+            ))
+        }
+    };
+    if let Some(code) = fields.get("room_code") {
+        let code = v1_room_code(code)?;
+        fields.insert(String::from("room_code"), Value::from(String::from(code)));
     }
+    fields.insert(String::from("event"), Value::String(event));
+    return serde_json::from_value::<ClientMsg>(Value::Object(fields))
+        .map_err(|err| (EventError::ParseError, err.to_string()));
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct WsMsg<E, M> {
-    event: E,
-    msg: M,
+fn decode_v2(text: &str) -> Result<ClientMsg, (EventError, String)> {
+    return serde_json::from_str::<ClientMsg>(text).map_err(|err| {
+        let message = err.to_string();
+        if message.starts_with("unknown variant") {
+            return (EventError::UnknownEvent, message);
+        }
+        return (EventError::ParseError, message);
+    });
 }
 
-#[derive(Deserialize, Serialize, Clone)]
-struct MoveMsg {
-    room_code: String,
-    i: u8,
-    j: u8,
-    k: u8,
-    l: u8,
+impl Socket {
+    fn encode(&self, msg: &MSG) -> String {
+        let v = match self.protocol {
+            Protocol::V1 => None,
+            Protocol::V2 => Some(self.protocol.version()),
+        };
+        let envelope = ServerEnvelope {
+            v,
+            msg: &msg.msg,
+            id: msg.id.as_ref(),
+            seq: msg.seq,
+        };
+        if self.protocol == Protocol::V2 {
+            return serde_json::to_string(&envelope).unwrap();
+        }
+        // v1 `msg` is always a string: the opponent's name in ConnectWith, the socket id
+        // in Start, the message text in errors, and JSON text for everything else.
+        let mut json = serde_json::to_value(&envelope).unwrap();
+        match &msg.msg {
+            ServerMsg::ConnectWith(connect) => json["msg"] = Value::from(connect.opponent.as_str()),
+            ServerMsg::Start(session) => {
+                json["msg"] = Value::from(session.id.as_str());
+                json["token"] = Value::from(session.token.as_str());
+                if let Some(ref user) = session.user {
+                    json["user"] = Value::from(user.as_str());
+                }
+            }
+            ServerMsg::Error(error) => {
+                json["code"] = Value::from(error.msg.code);
+                json["msg"] = Value::from(error.msg.message.as_str());
+            }
+            _ => {
+                if json["msg"].is_object() || json["msg"].is_array() {
+                    json["msg"] = Value::from(json["msg"].to_string());
+                }
+            }
+        }
+        return json.to_string();
+    }
+    // Turns a decoded client message into the matching server or room command.
+    fn dispatch(&mut self, msg: ClientMsg, request: &Value, ctx: &mut ws::WebsocketContext<Self>) {
+        let addr = self.addr.clone().unwrap();
+        let (room_code, command) = match msg {
+            ClientMsg::GetCode { name, options } => {
+                self.set_name(name);
+                let command = ServerCommands::AddRoom(self.clone(), options);
+                return self.send_command(request, command);
+            }
+            ClientMsg::Seek { name, options } => {
+                self.set_name(name);
+                let command = ServerCommands::Seek(self.clone(), options);
+                return self.send_command(request, command);
+            }
+            ClientMsg::CancelSeek => {
+                let command = ServerCommands::CancelSeek(addr, self.id.clone());
+                return self.send_command(request, command);
+            }
+            ClientMsg::SubscribeLobby => {
+                return self.send_command(request, ServerCommands::SubscribeLobby(addr));
+            }
+            ClientMsg::UnsubscribeLobby => {
+                return self.send_command(request, ServerCommands::UnsubscribeLobby(addr));
+            }
+            ClientMsg::ConnectWith {
+                room_code,
+                name,
+                password,
+            } => {
+                println!("passed code = {}", room_code);
+                self.set_name(name);
+                (room_code, RoomCommands::AddPlayer(self.clone(), password))
+            }
+            ClientMsg::OppReady { room_code } => {
+                println!("Opp Ready");
+                (room_code, RoomCommands::OppReady)
+            }
+            ClientMsg::Move {
+                room_code,
+                i,
+                j,
+                k,
+                l,
+                promotion,
+            } => (room_code, RoomCommands::Move((i, j), (k, l), promotion)),
+            ClientMsg::Promote {
+                room_code,
+                i,
+                j,
+                promote_to,
+            } => {
+                let valid_promote = ["H", "B", "Q", "R"].contains(&promote_to.as_str());
+                if !((i == 0 || i == 7) && j < 8 && valid_promote) {
+                    let msg = MSG::error(EventError::IllegalMove, "Invalid promotion", request);
+                    ctx.text(self.encode(&msg));
+                    return;
+                }
+                (room_code, RoomCommands::Promote((i, j), promote_to))
+            }
+            ClientMsg::PromoteReq { room_code } => (room_code, RoomCommands::PromoteReq),
+            ClientMsg::Fen { room_code } => (room_code, RoomCommands::Fen),
+            ClientMsg::Rejoin { room_code, token } => {
+                (room_code, RoomCommands::Rejoin(self.clone(), token))
+            }
+            ClientMsg::Spectate {
+                room_code,
+                password,
+            } => (room_code, RoomCommands::Spectate(self.clone(), password)),
+            ClientMsg::Resign { room_code } => (room_code, RoomCommands::Resign),
+            ClientMsg::OfferDraw { room_code } => (room_code, RoomCommands::OfferDraw),
+            ClientMsg::AcceptDraw { room_code } => (room_code, RoomCommands::AcceptDraw),
+            ClientMsg::DeclineDraw { room_code } => (room_code, RoomCommands::DeclineDraw),
+            ClientMsg::Abort { room_code } => (room_code, RoomCommands::Abort),
+            ClientMsg::TakebackRequest { room_code } => (room_code, RoomCommands::TakebackRequest),
+            ClientMsg::TakebackAccept { room_code } => (room_code, RoomCommands::TakebackAccept),
+            ClientMsg::Sync { room_code } => (room_code, RoomCommands::Sync),
+            ClientMsg::RematchOffer { room_code } => (room_code, RoomCommands::RematchOffer),
+            ClientMsg::RematchAccept { room_code } => (room_code, RoomCommands::RematchAccept),
+        };
+        self.send_room_command(request, room_code, command, ctx);
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Socket {
@@ -415,174 +2798,36 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Socket {
                 ws::Message::Text(text) => {
                     let text_string = text.to_string();
                     println!("{}", text_string);
-                    let msg_struct = serde_json::from_str::<WsMsg<String, String>>(&text_string);
-
-                    match msg_struct {
-                        Ok(socket_msg) => {
-                            let event = socket_msg.event;
-                            let msg = socket_msg.msg;
-                            match Event::from_string(&event) {
-                                Ok(event) => match event {
-                                    Event::Move => {
-                                        let msg_struct =
-                                            serde_json::from_str::<WsMsg<String, String>>(
-                                                &text_string,
-                                            );
-                                        let mv = serde_json::from_str::<MoveMsg>(
-                                            &msg_struct.unwrap().msg,
-                                        );
-                                        if let Ok(mv) = mv {
-                                            let room_code = mv.room_code.parse::<u16>();
-                                            if let Ok(room_code) = room_code {
-                                                self.server.do_send(ServerCommands::Move(
-                                                    self.addr.clone().unwrap(),
-                                                    self.clone().id,
-                                                    room_code,
-                                                    (mv.i, mv.j),
-                                                    (mv.k, mv.l),
-                                                ))
-                                            } else {
-                                                let msg = create_ws_msg(
-                                                    EventOrError::EventError(
-                                                        EventError::ParseError,
-                                                    ),
-                                                    &"Invalid room code",
-                                                )
-                                                .unwrap();
-                                                ctx.text(msg);
-                                            }
-                                        } else {
-                                            let msg = create_ws_msg(
-                                                EventOrError::EventError(EventError::ParseError),
-                                                &"Invalid json",
-                                            )
-                                            .unwrap();
-                                            ctx.text(msg);
-                                        }
-                                    }
-                                    Event::GameOver => ctx.text("gameover"),
-                                    Event::Start => ctx.text("starting"),
-                                    Event::ConnectWith => {
-                                        #[derive(Serialize, Deserialize)]
-                                        struct ConnectWithMsg {
-                                            room_code: String,
-                                            name: String,
-                                        }
-
-                                        let connect_msg =
-                                            serde_json::from_str::<ConnectWithMsg>(&msg);
-
-                                        if let Ok(connect_msg) = connect_msg {
-                                            let room_code =
-                                                connect_msg.room_code.trim().parse::<u16>();
-                                            if let Ok(code) = room_code {
-                                                println!("passed code = {}", code);
-                                                self.set_name(connect_msg.name);
-                                                self.server.do_send(
-                                                    ServerCommands::AddPlayerToRoom(
-                                                        self.clone(),
-                                                        code,
-                                                    ),
-                                                );
-                                            } else {
-                                                let msg = create_ws_msg(
-                                                    EventOrError::EventError(
-                                                        EventError::InvalidCode,
-                                                    ),
-                                                    &"Invalid room code",
-                                                )
-                                                .unwrap();
-                                                ctx.text(msg);
-                                            }
-                                        }
-                                    }
-                                    Event::GetCode => {
-                                        self.set_name(msg);
-                                        self.server.do_send(ServerCommands::AddRoom(self.clone()));
-                                    }
-                                    Event::OppReady => {
-                                        println!("{}", msg);
-                                        let code = msg.trim().parse::<u16>();
-                                        println!("Opp Ready");
-                                        if let Ok(code) = code {
-                                            self.server.do_send(ServerCommands::OppReady(
-                                                self.clone(),
-                                                code,
-                                            ));
-                                        } else {
-                                            let msg = create_ws_msg(
-                                                EventOrError::EventError(EventError::ParseError),
-                                                &"Invalid Room Code",
-                                            )
-                                            .unwrap();
-                                            ctx.text(msg);
-                                        }
-                                    }
-                                    Event::Promote => {
-                                        #[derive(Deserialize)]
-                                        struct PromoteMsg {
-                                            room_code: String,
-                                            i: u8,
-                                            j: u8,
-                                            promote_to: String,
-                                        }
-                                        let promote_msg = serde_json::from_str::<PromoteMsg>(&msg);
-                                        if let Ok(promote_msg) = promote_msg {
-                                            let PromoteMsg {
-                                                room_code,
-                                                i,
-                                                j,
-                                                promote_to,
-                                            } = promote_msg;
-                                            let mut valid_promote = false;
-                                            for k in ["H", "B", "Q", "R"] {
-                                                if k == promote_to {
-                                                    valid_promote = true;
-                                                }
-                                            }
-                                            // ["H", "B", "Q", "R"].iter().collect();
-                                            if let Ok(room_code) = room_code.parse::<u16>() {
-                                                if (i == 0 || i == 7) && j < 8 && valid_promote {
-                                                    self.server.do_send(ServerCommands::Promote(
-                                                        room_code,
-                                                        self.clone().id,
-                                                        (i, j),
-                                                        promote_to,
-                                                    ))
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Event::PromoteReq => {
-                                        // println!()
-                                        // struct PromoteReqReq {
-                                        //     room_code: String,
-                                        // }
-                                    }
-                                },
-                                Err(event_error) => match event_error {
-                                    EventError::ParseError => {
-                                        let res = create_ws_msg(
-                                            EventOrError::EventError(event_error),
-                                            &"Error While Parsing Event",
-                                        )
-                                        .unwrap();
-                                        ctx.text(res);
-                                    }
-                                    _ => {}
-                                },
-                            }
-                        }
-                        Err(err) => {
-                            let res = create_ws_msg(
-                                EventOrError::EventError(EventError::ParseError),
-                                &err.to_string(),
-                            )
-                            .unwrap();
-                            ctx.text(res);
+                    let request = serde_json::from_str::<Value>(&text_string)
+                        .unwrap_or(Value::String(text_string.clone()));
+                    if self.rate_limited() {
+                        let msg = MSG::error(
+                            EventError::RateLimited,
+                            "Too many messages, slow down",
+                            &request,
+                        );
+                        ctx.text(self.encode(&msg));
+                        return;
+                    }
+                    let decoded = match self.protocol {
+                        Protocol::V1 => decode_v1(&text_string),
+                        Protocol::V2 => decode_v2(&text_string),
+                    };
+                    match decoded {
+                        Ok(msg) => self.dispatch(msg, &request, ctx),
+                        Err((error, message)) => {
+                            let msg = MSG::error(error, &message, &request);
+                            ctx.text(self.encode(&msg));
                         }
                     }
                 }
+                ws::Message::Ping(bytes) => ctx.pong(&bytes),
+                ws::Message::Pong(_) => {}
+                // Stopping the actor runs `stopped`, which leaves the rooms as usual.
+                ws::Message::Close(reason) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
                 _ => {
                     ctx.text("Unknown format");
                 }
@@ -594,28 +2839,75 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Socket {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-enum EventOrError {
-    Event(Event),
-    EventError(EventError),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::{Arc, Mutex};
 
-fn create_ws_msg<T>(event: EventOrError, msg: &T) -> Result<String, serde_json::Error>
-where
-    T: Serialize,
-{
-    match event {
-        EventOrError::Event(event) => {
-            return serde_json::to_string(&WsMsg {
-                event: event.to_string(),
-                msg,
-            })
-        }
-        EventOrError::EventError(err) => {
-            return serde_json::to_string(&WsMsg {
-                event: err.to_string(),
-                msg,
+    fn player(id: &str, name: &str) -> PlayerRecord {
+        return PlayerRecord {
+            id: String::from(id),
+            token: format!("{}-token", id),
+            name: String::from(name),
+            user_id: None,
+        };
+    }
+
+    #[test]
+    fn restore_room() {
+        let mut memory = MemoryStorage::default();
+        let id = memory
+            .create_room(&RoomRecord {
+                code: RoomCode::random(),
+                white: Some(player("p1", "alice")),
+                black: Some(player("p2", "bob")),
+                creator_color: Color::White,
+                start_fen: Board::new().to_fen(),
+                time_control: Some(TimeControl::try_from(String::from("5+3")).unwrap()),
+                casual: false,
+                rated: true,
+                public: true,
+                created_at: 1_700_000_000_000,
+                access: Access::default(),
             })
+            .unwrap();
+        for (ply, uci) in ["e2e4", "e7e5", "g1f3"].iter().enumerate() {
+            let clock = ClockTimes {
+                white: 300_000 - 2000 * ply as u64,
+                black: 299_000,
+            };
+            let mv = MoveRow {
+                uci: String::from(*uci),
+                san: String::new(),
+                timestamp: 1_700_000_001_000,
+                clock: Some(clock),
+            };
+            memory.add_move(id, ply, &mv).unwrap();
         }
+        let game = memory.active_games().unwrap().pop().unwrap();
+        let storage: SharedStorage = Arc::new(Mutex::new(memory));
+
+        actix::System::new().block_on(async move {
+            // The room only sends to these, so they need not be running.
+            let server = actix::Context::<Server>::new().address();
+            let writer = actix::Context::<Writer>::new().address();
+            let room = Room::restore(game, server, storage, writer).unwrap();
+
+            assert_eq!(
+                room.board.to_fen(),
+                "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2"
+            );
+            assert_eq!(room.moves.len(), 3);
+            assert_eq!(room.turn, "p2");
+            let clock = room.clock.as_ref().unwrap();
+            assert_eq!(clock.running_side(), Some(Color::Black));
+            assert_eq!(clock.remaining(Color::White), 296_000);
+            assert!(clock.remaining(Color::Black) > 298_000);
+            assert!(room.away.contains_key("p1") && room.away.contains_key("p2"));
+            assert!(room.rated && room.public);
+            assert_eq!(room.saved.key, GameKey::Stored(id));
+            assert_eq!(room.saved.moves, 3);
+        });
     }
 }
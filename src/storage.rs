@@ -0,0 +1,999 @@
+use actix::{Actor, AsyncContext, Handler, Message};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::Access;
+use crate::chess::Color;
+use crate::clock::{ClockTimes, TimeControl};
+use crate::rating::{Category, Rating};
+use crate::room_code::RoomCode;
+
+#[derive(Clone)]
+pub struct PlayerRecord {
+    pub id: String,
+    pub token: String,
+    pub name: String,
+    // The account the player was logged in with; None for guests.
+    pub user_id: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct UserRecord {
+    pub id: i64,
+    pub name: String,
+    // Argon2 hash in PHC string format.
+    pub password_hash: String,
+}
+
+#[derive(Clone)]
+pub struct RoomRecord {
+    pub code: RoomCode,
+    // Either seat may still be open, depending on the side the creator took.
+    pub white: Option<PlayerRecord>,
+    pub black: Option<PlayerRecord>,
+    pub creator_color: Color,
+    pub start_fen: String,
+    pub time_control: Option<TimeControl>,
+    pub casual: bool,
+    pub rated: bool,
+    // Listed in the lobby while a seat is open.
+    pub public: bool,
+    // Milliseconds since the unix epoch.
+    pub created_at: u64,
+    pub access: Access,
+}
+
+#[derive(Clone)]
+pub struct MoveRow {
+    pub uci: String,
+    pub san: String,
+    pub timestamp: u64,
+    // Both clocks right after the move.
+    pub clock: Option<ClockTimes>,
+}
+
+// A game that had no result when it was last saved.
+pub struct ActiveGame {
+    pub id: i64,
+    pub room: RoomRecord,
+    pub moves: Vec<MoveRow>,
+}
+
+// Where games, players and ratings outlive a restart. Rooms are identified by the id
+// `create_room` returns, since room codes are reused over time.
+pub trait Storage: Send {
+    fn create_room(&mut self, room: &RoomRecord) -> Result<i64, String>;
+    // Saves the players and options again, e.g. once the second player has joined.
+    fn update_room(&mut self, id: i64, room: &RoomRecord) -> Result<(), String>;
+    fn add_move(&mut self, id: i64, ply: usize, mv: &MoveRow) -> Result<(), String>;
+    // Drops every move from `plies` on, after a takeback.
+    fn truncate_moves(&mut self, id: i64, plies: usize) -> Result<(), String>;
+    fn set_result(&mut self, id: i64, result: &str, reason: &str) -> Result<(), String>;
+    fn delete_room(&mut self, id: i64) -> Result<(), String>;
+    fn active_games(&self) -> Result<Vec<ActiveGame>, String>;
+    fn save_rating(&mut self, name: &str, category: Category, rating: Rating)
+        -> Result<(), String>;
+    fn rating(&self, name: &str, category: Category) -> Result<Option<Rating>, String>;
+    // None when the name is already taken, ignoring case.
+    fn create_user(&mut self, name: &str, password_hash: &str) -> Result<Option<i64>, String>;
+    fn user(&self, name: &str) -> Result<Option<UserRecord>, String>;
+    // Groups the writes up to `commit` into one transaction where storage supports it.
+    fn begin(&mut self) -> Result<(), String> {
+        return Ok(());
+    }
+    fn commit(&mut self) -> Result<(), String> {
+        return Ok(());
+    }
+}
+
+// One storage shared by the server, the writer and every room actor.
+pub type SharedStorage = Arc<Mutex<dyn Storage>>;
+
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::White => return "white",
+        Color::Black => return "black",
+    }
+}
+
+fn category_name(category: Category) -> &'static str {
+    match category {
+        Category::Bullet => return "bullet",
+        Category::Blitz => return "blitz",
+        Category::Rapid => return "rapid",
+        Category::Classical => return "classical",
+    }
+}
+
+fn access_json(access: &Access) -> Option<String> {
+    if access.is_open() {
+        return None;
+    }
+    return Some(serde_json::to_string(access).unwrap());
+}
+
+const ROOMS_COLUMNS: &str = "(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL,
+    white_id TEXT REFERENCES players(id),
+    black_id TEXT REFERENCES players(id),
+    creator_color TEXT NOT NULL,
+    start_fen TEXT NOT NULL,
+    time_control TEXT,
+    casual INTEGER NOT NULL,
+    rated INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    result TEXT,
+    reason TEXT,
+    -- Access as JSON, NULL when anyone with the code may join.
+    access TEXT,
+    public INTEGER NOT NULL DEFAULT 0
+)";
+
+// Bumped with every step in `migrate`.
+const SCHEMA_VERSION: i64 = 4;
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    return Ok(columns.iter().any(|name| name == column));
+}
+
+// Brings tables made by older versions up to the current schema; new tables are
+// already created that way. Databases from before `user_version` was kept report 0,
+// so each step checks for its column instead of trusting the version alone.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+    // Rebuilding `rooms` below must not cascade into `moves`, and this cannot change
+    // inside a transaction.
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let tx = conn.unchecked_transaction()?;
+    // 1: accounts.
+    if version < 1 && !has_column(&tx, "players", "user_id")? {
+        tx.execute_batch("ALTER TABLE players ADD COLUMN user_id INTEGER REFERENCES users(id);")?;
+    }
+    // 2: private rooms.
+    if version < 2 && !has_column(&tx, "rooms", "access")? {
+        tx.execute_batch("ALTER TABLE rooms ADD COLUMN access TEXT;")?;
+    }
+    // 3: color choice. White's seat may now be open, and SQLite can only drop NOT NULL
+    // by copying the table; earlier rooms all had the creator playing white.
+    if version < 3 && !has_column(&tx, "rooms", "creator_color")? {
+        tx.execute_batch(&format!(
+            "CREATE TABLE rooms_new {};
+            INSERT INTO rooms_new (id, code, white_id, black_id, creator_color, start_fen,
+                time_control, casual, rated, created_at, result, reason, access)
+            SELECT id, code, white_id, black_id, 'white', start_fen, time_control, casual,
+                rated, created_at, result, reason, access FROM rooms;
+            DROP TABLE rooms;
+            ALTER TABLE rooms_new RENAME TO rooms;",
+            ROOMS_COLUMNS
+        ))?;
+    }
+    // 4: lobby listing, so restored rooms with an open seat show up again.
+    if version < 4 && !has_column(&tx, "rooms", "public")? {
+        tx.execute_batch("ALTER TABLE rooms ADD COLUMN public INTEGER NOT NULL DEFAULT 0;")?;
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))?;
+    return tx.commit();
+}
+
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS players (
+                id TEXT PRIMARY KEY,
+                token TEXT NOT NULL,
+                name TEXT NOT NULL,
+                user_id INTEGER REFERENCES users(id)
+            );
+            CREATE TABLE IF NOT EXISTS rooms {};
+            CREATE TABLE IF NOT EXISTS moves (
+                room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+                ply INTEGER NOT NULL,
+                uci TEXT NOT NULL,
+                san TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                white_ms INTEGER,
+                black_ms INTEGER,
+                PRIMARY KEY (room_id, ply)
+            );
+            CREATE TABLE IF NOT EXISTS ratings (
+                name TEXT NOT NULL,
+                category TEXT NOT NULL,
+                rating REAL NOT NULL,
+                deviation REAL NOT NULL,
+                volatility REAL NOT NULL,
+                PRIMARY KEY (name, category)
+            );",
+            ROOMS_COLUMNS
+        ))
+        .map_err(|e| e.to_string())?;
+        migrate(&conn).map_err(|e| format!("migrating {}: {}", path, e))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(|e| e.to_string())?;
+        return Ok(SqliteStorage { conn });
+    }
+
+    fn save_player(&self, player: &PlayerRecord) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO players (id, token, name, user_id) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT(id) DO UPDATE SET token = ?2, name = ?3, user_id = ?4",
+                params![player.id, player.token, player.name, player.user_id],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn player(&self, id: &str) -> Result<PlayerRecord, String> {
+        return self
+            .conn
+            .query_row(
+                "SELECT id, token, name, user_id FROM players WHERE id = ?1",
+                params![id],
+                |row| {
+                    return Ok(PlayerRecord {
+                        id: row.get(0)?,
+                        token: row.get(1)?,
+                        name: row.get(2)?,
+                        user_id: row.get(3)?,
+                    });
+                },
+            )
+            .map_err(|e| e.to_string());
+    }
+
+    fn save_players(&self, room: &RoomRecord) -> Result<(), String> {
+        for player in room.white.iter().chain(room.black.iter()) {
+            self.save_player(player)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn create_room(&mut self, room: &RoomRecord) -> Result<i64, String> {
+        self.save_players(room)?;
+        self.conn
+            .execute(
+                "INSERT INTO rooms (code, white_id, black_id, creator_color, start_fen,
+                time_control, casual, rated, created_at, access, public)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    String::from(room.code.clone()),
+                    room.white.as_ref().map(|white| &white.id),
+                    room.black.as_ref().map(|black| &black.id),
+                    color_name(room.creator_color),
+                    room.start_fen,
+                    room.time_control.map(String::from),
+                    room.casual,
+                    room.rated,
+                    room.created_at as i64,
+                    access_json(&room.access),
+                    room.public,
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(self.conn.last_insert_rowid());
+    }
+
+    fn update_room(&mut self, id: i64, room: &RoomRecord) -> Result<(), String> {
+        self.save_players(room)?;
+        self.conn
+            .execute(
+                "UPDATE rooms SET white_id = ?2, black_id = ?3 WHERE id = ?1",
+                params![
+                    id,
+                    room.white.as_ref().map(|white| &white.id),
+                    room.black.as_ref().map(|black| &black.id)
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn add_move(&mut self, id: i64, ply: usize, mv: &MoveRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO moves (room_id, ply, uci, san, timestamp, white_ms,
+                black_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    id,
+                    ply as i64,
+                    mv.uci,
+                    mv.san,
+                    mv.timestamp as i64,
+                    mv.clock.map(|clock| clock.white as i64),
+                    mv.clock.map(|clock| clock.black as i64),
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn truncate_moves(&mut self, id: i64, plies: usize) -> Result<(), String> {
+        self.conn
+            .execute(
+                "DELETE FROM moves WHERE room_id = ?1 AND ply >= ?2",
+                params![id, plies as i64],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn set_result(&mut self, id: i64, result: &str, reason: &str) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE rooms SET result = ?2, reason = ?3 WHERE id = ?1",
+                params![id, result, reason],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn delete_room(&mut self, id: i64) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM rooms WHERE id = ?1", params![id])
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn begin(&mut self) -> Result<(), String> {
+        return self.conn.execute_batch("BEGIN").map_err(|e| e.to_string());
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        return self.conn.execute_batch("COMMIT").map_err(|e| e.to_string());
+    }
+
+    fn active_games(&self) -> Result<Vec<ActiveGame>, String> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT id, CAST(code AS TEXT), white_id, black_id, start_fen, time_control,
+                casual, rated, created_at, access, creator_color, public FROM rooms
+                WHERE result IS NULL ORDER BY id",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = statement
+            .query_map([], |row| {
+                return Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, bool>(6)?,
+                    row.get::<_, bool>(7)?,
+                    row.get::<_, i64>(8)?,
+                    row.get::<_, Option<String>>(9)?,
+                    row.get::<_, String>(10)?,
+                    row.get::<_, bool>(11)?,
+                ));
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let mut games = Vec::new();
+        for (
+            id,
+            code,
+            white_id,
+            black_id,
+            start_fen,
+            time_control,
+            casual,
+            rated,
+            created_at,
+            access,
+            creator_color,
+            public,
+        ) in rows
+        {
+            // Rooms from when codes were numbers cannot be rejoined with a new client.
+            let code = match RoomCode::try_from(code) {
+                Ok(code) => code,
+                Err(_) => continue,
+            };
+            let white = match white_id {
+                Some(white_id) => Some(self.player(&white_id)?),
+                None => None,
+            };
+            let black = match black_id {
+                Some(black_id) => Some(self.player(&black_id)?),
+                None => None,
+            };
+            let time_control = match time_control {
+                Some(tc) => Some(TimeControl::try_from(tc)?),
+                None => None,
+            };
+            let mut statement = self
+                .conn
+                .prepare(
+                    "SELECT uci, san, timestamp, white_ms, black_ms FROM moves
+                    WHERE room_id = ?1 ORDER BY ply",
+                )
+                .map_err(|e| e.to_string())?;
+            let moves = statement
+                .query_map(params![id], |row| {
+                    let white: Option<i64> = row.get(3)?;
+                    let black: Option<i64> = row.get(4)?;
+                    return Ok(MoveRow {
+                        uci: row.get(0)?,
+                        san: row.get(1)?,
+                        timestamp: row.get::<_, i64>(2)? as u64,
+                        clock: white.zip(black).map(|(white, black)| ClockTimes {
+                            white: white as u64,
+                            black: black as u64,
+                        }),
+                    });
+                })
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?;
+            games.push(ActiveGame {
+                id,
+                room: RoomRecord {
+                    code,
+                    white,
+                    black,
+                    creator_color: match creator_color.as_str() {
+                        "black" => Color::Black,
+                        _ => Color::White,
+                    },
+                    start_fen,
+                    time_control,
+                    casual,
+                    rated,
+                    public,
+                    created_at: created_at as u64,
+                    access: match access {
+                        Some(access) => serde_json::from_str(&access).map_err(|e| e.to_string())?,
+                        None => Access::default(),
+                    },
+                },
+                moves,
+            });
+        }
+        return Ok(games);
+    }
+
+    fn save_rating(
+        &mut self,
+        name: &str,
+        category: Category,
+        rating: Rating,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO ratings (name, category, rating, deviation, volatility)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    name,
+                    category_name(category),
+                    rating.rating,
+                    rating.deviation,
+                    rating.volatility,
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    fn rating(&self, name: &str, category: Category) -> Result<Option<Rating>, String> {
+        return self
+            .conn
+            .query_row(
+                "SELECT rating, deviation, volatility FROM ratings
+                WHERE name = ?1 AND category = ?2",
+                params![name, category_name(category)],
+                |row| {
+                    return Ok(Rating {
+                        rating: row.get(0)?,
+                        deviation: row.get(1)?,
+                        volatility: row.get(2)?,
+                    });
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string());
+    }
+
+    fn create_user(&mut self, name: &str, password_hash: &str) -> Result<Option<i64>, String> {
+        let inserted = self
+            .conn
+            .execute(
+                "INSERT INTO users (name, password_hash, created_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(name) DO NOTHING",
+                params![
+                    name,
+                    password_hash,
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis() as i64
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(self.conn.last_insert_rowid()));
+    }
+
+    fn user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        return self
+            .conn
+            .query_row(
+                "SELECT id, name, password_hash FROM users WHERE name = ?1",
+                params![name],
+                |row| {
+                    return Ok(UserRecord {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        password_hash: row.get(2)?,
+                    });
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string());
+    }
+}
+
+struct StoredRoom {
+    room: RoomRecord,
+    moves: Vec<MoveRow>,
+    finished: bool,
+}
+
+// Keeps everything in memory; used for tests and when the database cannot be opened.
+#[derive(Default)]
+pub struct MemoryStorage {
+    next_id: i64,
+    rooms: HashMap<i64, StoredRoom>,
+    ratings: HashMap<(String, Category), Rating>,
+    users: Vec<UserRecord>,
+}
+
+impl MemoryStorage {
+    fn room(&mut self, id: i64) -> Result<&mut StoredRoom, String> {
+        return self
+            .rooms
+            .get_mut(&id)
+            .ok_or_else(|| format!("No stored room {}", id));
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create_room(&mut self, room: &RoomRecord) -> Result<i64, String> {
+        self.next_id += 1;
+        self.rooms.insert(
+            self.next_id,
+            StoredRoom {
+                room: room.clone(),
+                moves: Vec::new(),
+                finished: false,
+            },
+        );
+        return Ok(self.next_id);
+    }
+
+    fn update_room(&mut self, id: i64, room: &RoomRecord) -> Result<(), String> {
+        self.room(id)?.room = room.clone();
+        return Ok(());
+    }
+
+    fn add_move(&mut self, id: i64, ply: usize, mv: &MoveRow) -> Result<(), String> {
+        let moves = &mut self.room(id)?.moves;
+        moves.truncate(ply);
+        moves.push(mv.clone());
+        return Ok(());
+    }
+
+    fn truncate_moves(&mut self, id: i64, plies: usize) -> Result<(), String> {
+        self.room(id)?.moves.truncate(plies);
+        return Ok(());
+    }
+
+    fn set_result(&mut self, id: i64, _result: &str, _reason: &str) -> Result<(), String> {
+        self.room(id)?.finished = true;
+        return Ok(());
+    }
+
+    fn delete_room(&mut self, id: i64) -> Result<(), String> {
+        self.rooms.remove(&id);
+        return Ok(());
+    }
+
+    fn active_games(&self) -> Result<Vec<ActiveGame>, String> {
+        let mut games: Vec<ActiveGame> = self
+            .rooms
+            .iter()
+            .filter(|(_, stored)| !stored.finished)
+            .map(|(id, stored)| ActiveGame {
+                id: *id,
+                room: stored.room.clone(),
+                moves: stored.moves.clone(),
+            })
+            .collect();
+        games.sort_by_key(|game| game.id);
+        return Ok(games);
+    }
+
+    fn save_rating(
+        &mut self,
+        name: &str,
+        category: Category,
+        rating: Rating,
+    ) -> Result<(), String> {
+        self.ratings.insert((String::from(name), category), rating);
+        return Ok(());
+    }
+
+    fn rating(&self, name: &str, category: Category) -> Result<Option<Rating>, String> {
+        return Ok(self.ratings.get(&(String::from(name), category)).copied());
+    }
+
+    fn create_user(&mut self, name: &str, password_hash: &str) -> Result<Option<i64>, String> {
+        if self.user(name)?.is_some() {
+            return Ok(None);
+        }
+        let id = self.users.len() as i64 + 1;
+        self.users.push(UserRecord {
+            id,
+            name: String::from(name),
+            password_hash: String::from(password_hash),
+        });
+        return Ok(Some(id));
+    }
+
+    fn user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        return Ok(self
+            .users
+            .iter()
+            .find(|user| user.name.eq_ignore_ascii_case(name))
+            .cloned());
+    }
+}
+
+// How long the writer collects changes before storing them in one transaction. A crash
+// loses at most this much play.
+const FLUSH_MS: u64 = 50;
+
+// Names a game to the writer. New games have no storage id until the writer has
+// created them, so rooms number them instead.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GameKey {
+    Stored(i64),
+    New(u64),
+}
+
+impl GameKey {
+    pub fn unsaved() -> GameKey {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        return GameKey::New(NEXT.fetch_add(1, Ordering::Relaxed));
+    }
+}
+
+// One change to a stored game.
+pub enum Write {
+    CreateRoom(RoomRecord),
+    UpdateRoom(RoomRecord),
+    AddMove(usize, MoveRow),
+    // Drops every move from this ply on.
+    TruncateMoves(usize),
+    SetResult(String, String),
+    DeleteRoom,
+}
+
+// Changes a room made to its game, in order.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Persist(pub GameKey, pub Vec<Write>);
+
+// Stores games for the room actors, so a slow disk never holds up play. Changes are
+// queued and written together every FLUSH_MS.
+pub struct Writer {
+    storage: SharedStorage,
+    // Storage ids of the new games created so far.
+    ids: HashMap<u64, i64>,
+    queue: Vec<(GameKey, Write)>,
+}
+
+impl Writer {
+    pub fn new(storage: SharedStorage) -> Writer {
+        return Writer {
+            storage,
+            ids: HashMap::new(),
+            queue: Vec::new(),
+        };
+    }
+    fn apply(
+        &mut self,
+        storage: &mut dyn Storage,
+        key: GameKey,
+        write: Write,
+    ) -> Result<(), String> {
+        let id = match key {
+            GameKey::Stored(id) => Some(id),
+            GameKey::New(key) => self.ids.get(&key).copied(),
+        };
+        if let Write::CreateRoom(room) = write {
+            if let GameKey::New(key) = key {
+                self.ids.insert(key, storage.create_room(&room)?);
+            }
+            return Ok(());
+        }
+        let id = id.ok_or_else(|| format!("{:?} was never stored", key))?;
+        match write {
+            Write::CreateRoom(_) => {}
+            Write::UpdateRoom(room) => storage.update_room(id, &room)?,
+            Write::AddMove(ply, mv) => storage.add_move(id, ply, &mv)?,
+            Write::TruncateMoves(plies) => storage.truncate_moves(id, plies)?,
+            // Nothing more is written for a game once it has a result or is gone.
+            Write::SetResult(result, reason) => {
+                storage.set_result(id, &result, &reason)?;
+                self.forget(key);
+            }
+            Write::DeleteRoom => {
+                storage.delete_room(id)?;
+                self.forget(key);
+            }
+        }
+        return Ok(());
+    }
+    fn forget(&mut self, key: GameKey) {
+        if let GameKey::New(key) = key {
+            self.ids.remove(&key);
+        }
+    }
+    fn flush(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        let storage = self.storage.clone();
+        let mut storage = storage.lock().unwrap();
+        if let Err(err) = storage.begin() {
+            println!("storage: {}", err);
+        }
+        for (key, write) in std::mem::take(&mut self.queue) {
+            if let Err(err) = self.apply(&mut *storage, key, write) {
+                println!("storage: {}", err);
+            }
+        }
+        if let Err(err) = storage.commit() {
+            println!("storage: {}", err);
+        }
+    }
+}
+
+impl Actor for Writer {
+    type Context = actix::Context<Self>;
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.flush();
+    }
+}
+
+impl Handler<Persist> for Writer {
+    type Result = ();
+    fn handle(&mut self, msg: Persist, ctx: &mut Self::Context) -> Self::Result {
+        if self.queue.is_empty() {
+            ctx.run_later(Duration::from_millis(FLUSH_MS), |writer, _ctx| {
+                writer.flush()
+            });
+        }
+        let Persist(key, writes) = msg;
+        self.queue
+            .extend(writes.into_iter().map(|write| (key, write)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::{self, Board};
+
+    fn player(id: &str, name: &str) -> PlayerRecord {
+        return PlayerRecord {
+            id: String::from(id),
+            token: format!("{}-token", id),
+            name: String::from(name),
+            user_id: None,
+        };
+    }
+
+    fn room() -> RoomRecord {
+        return RoomRecord {
+            code: RoomCode::random(),
+            white: None,
+            black: Some(player("p1", "alice")),
+            creator_color: Color::Black,
+            start_fen: Board::new().to_fen(),
+            time_control: Some(TimeControl::try_from(String::from("5+3")).unwrap()),
+            casual: false,
+            rated: true,
+            public: true,
+            created_at: 1_700_000_000_000,
+            access: Access::new(Some("secret"), None, Vec::new()),
+        };
+    }
+
+    fn move_row(uci: &str, white: u64, black: u64) -> MoveRow {
+        return MoveRow {
+            uci: String::from(uci),
+            san: String::new(),
+            timestamp: 1_700_000_001_000,
+            clock: Some(ClockTimes { white, black }),
+        };
+    }
+
+    // Plays the stored moves from the start position, as the server does on restart.
+    fn replay(game: &ActiveGame) -> Board {
+        let mut board = Board::from_fen(&game.room.start_fen).unwrap();
+        for mv in game.moves.iter() {
+            let ((from, to), promotion) = chess::parse_uci(&mv.uci).unwrap();
+            board.make_move(from, to, promotion).unwrap();
+        }
+        return board;
+    }
+
+    fn round_trip(storage: &mut dyn Storage) {
+        let mut record = room();
+        let id = storage.create_room(&record).unwrap();
+        record.white = Some(player("p2", "bob"));
+        storage.update_room(id, &record).unwrap();
+        for (ply, uci) in ["e2e4", "e7e5", "g1f3", "b8c6"].iter().enumerate() {
+            storage
+                .add_move(
+                    id,
+                    ply,
+                    &move_row(uci, 300_000, 299_000 - 1000 * ply as u64),
+                )
+                .unwrap();
+        }
+
+        let games = storage.active_games().unwrap();
+        assert_eq!(games.len(), 1);
+        let game = &games[0];
+        assert_eq!(game.id, id);
+        assert_eq!(game.room.code, record.code);
+        assert_eq!(game.room.white.as_ref().unwrap().name, "bob");
+        assert_eq!(game.room.black.as_ref().unwrap().token, "p1-token");
+        assert_eq!(game.room.creator_color, Color::Black);
+        assert_eq!(game.room.time_control, record.time_control);
+        assert!(game.room.rated && !game.room.casual && game.room.public);
+        assert_eq!(game.room.created_at, record.created_at);
+        assert!(game.room.access.check("bob", None, Some("secret")).is_ok());
+        assert!(game.room.access.check("bob", None, Some("guess")).is_err());
+        let uci: Vec<&str> = game.moves.iter().map(|mv| mv.uci.as_str()).collect();
+        assert_eq!(uci, ["e2e4", "e7e5", "g1f3", "b8c6"]);
+        assert_eq!(game.moves[3].clock.unwrap().black, 296_000);
+        assert_eq!(
+            replay(game).to_fen(),
+            "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3"
+        );
+
+        // A takeback drops the last move, and the replacement takes its ply.
+        storage.truncate_moves(id, 3).unwrap();
+        storage
+            .add_move(id, 3, &move_row("g8f6", 300_000, 298_000))
+            .unwrap();
+        let games = storage.active_games().unwrap();
+        let uci: Vec<&str> = games[0].moves.iter().map(|mv| mv.uci.as_str()).collect();
+        assert_eq!(uci, ["e2e4", "e7e5", "g1f3", "g8f6"]);
+        assert_eq!(
+            replay(&games[0]).to_fen(),
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3"
+        );
+
+        // Finished and deleted rooms are not restored.
+        let other = storage.create_room(&room()).unwrap();
+        storage.set_result(id, "1-0", "Resignation").unwrap();
+        let games = storage.active_games().unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].id, other);
+        storage.delete_room(other).unwrap();
+        assert!(storage.active_games().unwrap().is_empty());
+    }
+
+    fn ratings_and_users(storage: &mut dyn Storage) {
+        assert!(storage.rating("alice", Category::Blitz).unwrap().is_none());
+        let rating = Rating {
+            rating: 1612.5,
+            deviation: 80.0,
+            volatility: 0.059,
+        };
+        storage
+            .save_rating("alice", Category::Blitz, rating)
+            .unwrap();
+        let saved = storage.rating("alice", Category::Blitz).unwrap().unwrap();
+        assert_eq!(saved.rating, 1612.5);
+        assert_eq!(saved.deviation, 80.0);
+        assert!(storage.rating("alice", Category::Rapid).unwrap().is_none());
+
+        let id = storage.create_user("Alice", "hash").unwrap().unwrap();
+        assert!(storage.create_user("alice", "other").unwrap().is_none());
+        let user = storage.user("ALICE").unwrap().unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(user.name, "Alice");
+        assert_eq!(user.password_hash, "hash");
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        round_trip(&mut MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_round_trip() {
+        round_trip(&mut SqliteStorage::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn memory_ratings_and_users() {
+        ratings_and_users(&mut MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_ratings_and_users() {
+        ratings_and_users(&mut SqliteStorage::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn writer_stores_new_games() {
+        let storage: SharedStorage = Arc::new(Mutex::new(SqliteStorage::open(":memory:").unwrap()));
+        let writer_storage = storage.clone();
+        actix::System::new().block_on(async move {
+            let writer = Writer::new(writer_storage).start();
+            let (kept, dropped) = (GameKey::unsaved(), GameKey::unsaved());
+            writer.do_send(Persist(kept, vec![Write::CreateRoom(room())]));
+            writer.do_send(Persist(dropped, vec![Write::CreateRoom(room())]));
+            writer.do_send(Persist(
+                kept,
+                vec![
+                    Write::AddMove(0, move_row("e2e4", 300_000, 300_000)),
+                    Write::AddMove(1, move_row("c7c5", 300_000, 299_000)),
+                ],
+            ));
+            writer.do_send(Persist(dropped, vec![Write::DeleteRoom]));
+            writer.do_send(Persist(kept, vec![Write::TruncateMoves(1)]));
+            actix::clock::sleep(Duration::from_millis(FLUSH_MS * 3)).await;
+        });
+        let games = storage.lock().unwrap().active_games().unwrap();
+        assert_eq!(games.len(), 1);
+        let uci: Vec<&str> = games[0].moves.iter().map(|mv| mv.uci.as_str()).collect();
+        assert_eq!(uci, ["e2e4"]);
+    }
+
+    #[test]
+    fn sqlite_open_is_current() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let version: i64 = storage
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+}